@@ -0,0 +1,356 @@
+// (c) 2017 KAI OS TECHNOLOGIES (HONG KONG) LIMITED All rights reserved. This
+// file or any portion thereof may not be reproduced or used in any manner
+// whatsoever without the express written permission of KAI OS TECHNOLOGIES
+// (HONG KONG) LIMITED. KaiOS is the trademark of KAI OS TECHNOLOGIES (HONG KONG)
+// LIMITED or its affiliate company and may be registered in some jurisdictions.
+// All other trademarks are the property of their respective owners.
+
+/// Admin socket: a local Unix socket speaking the client Frame protocol, where
+/// each frame is a command getting a single reply frame.
+use config::Config;
+use frame::Frame;
+use frame_messages::{FilterFrame, SharedFilterFrame};
+use internal_messages::InternalMessage;
+use libc;
+use log::LevelFilter;
+use message_broker::SharedMessageBroker;
+use serde_json::Value;
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::io::{self, ErrorKind as IoErrorKind};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How long we wait for the other threads to answer.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+// How often we check for the Shutdown message while waiting for clients.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+// Clients are served one at a time, so an idle or stalled client can only
+// hold the thread that long.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminCommand {
+    ListSources,
+    QueueStats,
+    GetFilter,
+    SetFilter { filter: FilterFrame },
+    FlushQueue,
+    DropSource { source: String },
+    SetLogLevel { level: String },
+}
+
+/// The commands handled by the listener and the queue threads.
+#[derive(Clone, Debug)]
+pub enum AdminRequest {
+    ListSources,
+    DropSource(String),
+    QueueStats,
+    FlushQueue,
+}
+
+/// Sent back on the reply channel of an AdminRequest.
+pub type AdminResult = Result<Value, String>;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct AdminReply {
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<AdminResult> for AdminReply {
+    fn from(result: AdminResult) -> Self {
+        match result {
+            Ok(value) => AdminReply {
+                success: true,
+                result: Some(value),
+                error: None,
+            },
+            Err(error) => AdminReply {
+                success: false,
+                result: None,
+                error: Some(error),
+            },
+        }
+    }
+}
+
+/// Helper for the threads answering admin requests.
+pub fn reply(sender: &Sender<AdminResult>, result: AdminResult) {
+    if sender.send(result).is_err() {
+        error!("Admin client went away before the reply");
+    }
+}
+
+struct AdminServer {
+    broker: SharedMessageBroker<InternalMessage>,
+    filter: SharedFilterFrame,
+}
+
+impl AdminServer {
+    // Forwards a request to another thread and waits for its answer.
+    fn request(&self, target: &str, request: AdminRequest) -> AdminResult {
+        let (tx, rx) = channel();
+        self.broker
+            .lock()
+            .unwrap()
+            .send_message(target, InternalMessage::Admin(request, tx))
+            .map_err(|err| format!("{:?}", err))?;
+        rx.recv_timeout(REPLY_TIMEOUT)
+            .map_err(|_| format!("No reply from {}", target))?
+    }
+
+    fn execute(&self, command: AdminCommand) -> AdminResult {
+        match command {
+            AdminCommand::ListSources => self.request("listener", AdminRequest::ListSources),
+            AdminCommand::DropSource { source } => {
+                self.request("listener", AdminRequest::DropSource(source))
+            }
+            AdminCommand::QueueStats => self.request("queue", AdminRequest::QueueStats),
+            AdminCommand::FlushQueue => self.request("queue", AdminRequest::FlushQueue),
+            AdminCommand::GetFilter => Ok(json!(self.filter.lock().unwrap().get())),
+            AdminCommand::SetFilter { filter } => {
+                info!("Setting filter from the admin socket: {:?}", filter);
+                self.filter.lock().unwrap().set(filter);
+                self.broker
+                    .lock()
+                    .unwrap()
+                    .broadcast_message(InternalMessage::NewFilter(filter));
+                Ok(json!(filter))
+            }
+            AdminCommand::SetLogLevel { level } => {
+                let level: LevelFilter = level
+                    .parse()
+                    .map_err(|_| format!("Invalid log level: {}", level))?;
+                ::log::set_max_level(level);
+                info!("Log level set to {}", level);
+                Ok(json!(level.to_string()))
+            }
+        }
+    }
+
+    // Answers the commands of a client until it disconnects.
+    fn serve(&self, mut stream: UnixStream) {
+        loop {
+            let frame = match Frame::read_from(&mut stream) {
+                Ok(frame) => frame,
+                Err(_) => return,
+            };
+            let result = match frame.deserialize::<AdminCommand>() {
+                Ok(command) => {
+                    debug!("Admin command: {:?}", command);
+                    self.execute(command)
+                }
+                Err(err) => Err(format!("Invalid command: {}", err)),
+            };
+            let reply = Frame::encode(&AdminReply::from(result), frame.typ);
+            if reply.write_to(&mut stream).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Starts the admin socket thread, if there is an admin socket in the config.
+/// The thread removes the socket and exits when it gets the Shutdown message.
+pub fn start_admin(
+    config: &Config,
+    broker: SharedMessageBroker<InternalMessage>,
+    filter: SharedFilterFrame,
+) -> Option<JoinHandle<()>> {
+    let path = match config.admin_socket_path {
+        Some(ref path) => path.clone(),
+        None => return None,
+    };
+
+    if Path::new(&path).exists() {
+        let _ = fs::remove_file(&path);
+    }
+    // Only the daemon user can administer it, so the socket is created with
+    // no access for others rather than restricted after the fact.
+    let umask = unsafe { libc::umask(0o177) };
+    let socket = UnixListener::bind(&path);
+    unsafe { libc::umask(umask) };
+    let socket = match socket {
+        Ok(socket) => socket,
+        Err(err) => {
+            error!("Failed to bind the admin socket {}: {}", path, err);
+            return None;
+        }
+    };
+    let cpath = CString::new(path.clone()).unwrap();
+    if unsafe { libc::chmod(cpath.as_ptr(), 0o600) } != 0 {
+        error!(
+            "Failed to chmod 0600 {}, not starting the admin socket: {}",
+            path,
+            io::Error::last_os_error()
+        );
+        let _ = fs::remove_file(&path);
+        return None;
+    }
+    info!("Admin socket listening at {}", path);
+
+    let (tx, rx) = channel::<InternalMessage>();
+    if let Err(err) = broker.lock().unwrap().add_actor("admin", tx) {
+        error!("Failed to register the admin socket: {:?}", err);
+        return None;
+    }
+    socket
+        .set_nonblocking(true)
+        .expect("Failed to set the admin socket non blocking");
+
+    let server = AdminServer { broker, filter };
+    let thread = thread::Builder::new()
+        .name("admin socket".to_owned())
+        .spawn(move || {
+            // Admin clients are served one at a time.
+            loop {
+                match socket.accept() {
+                    Ok((stream, _)) => {
+                        let _ = stream.set_nonblocking(false);
+                        let _ = stream.set_read_timeout(Some(CLIENT_TIMEOUT));
+                        let _ = stream.set_write_timeout(Some(CLIENT_TIMEOUT));
+                        server.serve(stream);
+                    }
+                    Err(ref err) if err.kind() == IoErrorKind::WouldBlock => {
+                        thread::sleep(ACCEPT_INTERVAL)
+                    }
+                    Err(err) => error!("Admin socket accept failed: {}", err),
+                }
+                if rx
+                    .try_iter()
+                    .any(|msg| matches!(msg, InternalMessage::Shutdown))
+                {
+                    break;
+                }
+            }
+            info!("Shutting down admin socket");
+            let _ = server.broker.lock().unwrap().remove_actor("admin");
+            let _ = fs::remove_file(&path);
+        })
+        .expect("Failed to start admin socket thread");
+    Some(thread)
+}
+
+#[test]
+fn parse_commands() {
+    use serde_json;
+
+    let command: AdminCommand = serde_json::from_str(r#"{ "command": "list_sources" }"#).unwrap();
+    assert_eq!(command, AdminCommand::ListSources);
+    let command: AdminCommand =
+        serde_json::from_str(r#"{ "command": "drop_source", "source": "ril" }"#).unwrap();
+    assert_eq!(
+        command,
+        AdminCommand::DropSource {
+            source: "ril".into()
+        }
+    );
+    let command: AdminCommand = serde_json::from_str(
+        r#"{ "command": "set_filter", "filter": { "NC": 1, "ND": 2, "NE": 3 } }"#,
+    )
+    .unwrap();
+    match command {
+        AdminCommand::SetFilter { filter } => assert_eq!(filter.ne, 3),
+        _ => panic!("Not a set_filter command"),
+    }
+
+    let reply = AdminReply::from(Err("Nope".to_owned()));
+    assert_eq!(
+        serde_json::to_value(&reply).unwrap(),
+        json!({ "success": false, "error": "Nope" })
+    );
+}
+
+#[test]
+fn test_admin_socket() {
+    use frame_messages::{default_shared_filterframe, FilterFrame};
+    use listener::start_listener;
+    use message_broker::MessageBroker;
+    use queue::start_queue_manager;
+    use std::os::unix::fs::PermissionsExt;
+
+    let config = Config {
+        socket_path: "/tmp/metrics_daemon_8".to_owned(),
+        admin_socket_path: Some("/tmp/metrics_daemon_admin_8".to_owned()),
+        relay_port: 54327,
+        ..Config::default()
+    };
+
+    let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
+    let filter = default_shared_filterframe();
+    start_queue_manager(&config, broker.clone(), filter.clone());
+    start_listener(&config, broker.clone(), filter.clone());
+    let admin_thread = start_admin(&config, broker.clone(), filter);
+    thread::sleep(Duration::from_millis(200));
+    let mode = fs::metadata("/tmp/metrics_daemon_admin_8")
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // Connect a client.
+    let mut client = UnixStream::connect("/tmp/metrics_daemon_8").unwrap();
+    Frame::from_json(&json!({ "source": "test_source" }))
+        .write_to(&mut client)
+        .unwrap();
+    let _ready = Frame::read_from(&mut client).unwrap();
+    let _filter = Frame::read_from(&mut client).unwrap();
+
+    let mut admin = UnixStream::connect("/tmp/metrics_daemon_admin_8").unwrap();
+    let mut command = |command: Value| -> AdminReply {
+        Frame::from_json(&command).write_to(&mut admin).unwrap();
+        Frame::read_from(&mut admin).unwrap().deserialize().unwrap()
+    };
+
+    let reply = command(json!({ "command": "list_sources" }));
+    assert!(reply.success);
+    assert_eq!(reply.result, Some(json!(["test_source"])));
+
+    let reply = command(json!({ "command": "queue_stats" }));
+    assert!(reply.success);
+    assert_eq!(reply.result.unwrap()["relay_connected"], json!(false));
+
+    // New filters are pushed to the clients.
+    let reply =
+        command(json!({ "command": "set_filter", "filter": { "NC": 1, "ND": 2, "NE": 3 } }));
+    assert!(reply.success);
+    let pushed: FilterFrame = Frame::read_from(&mut client)
+        .unwrap()
+        .deserialize()
+        .unwrap();
+    assert_eq!(pushed.ne, 3);
+    let reply = command(json!({ "command": "get_filter" }));
+    assert_eq!(reply.result.unwrap()["NE"], json!(3));
+
+    let reply = command(json!({ "command": "flush_queue" }));
+    assert!(reply.success);
+
+    let reply = command(json!({ "command": "set_log_level", "level": "chatty" }));
+    assert!(!reply.success);
+
+    // Dropping the source closes its connection.
+    let reply = command(json!({ "command": "drop_source", "source": "test_source" }));
+    assert_eq!(reply.result, Some(json!({ "dropped": true })));
+    assert!(Frame::read_from(&mut client).is_err());
+    let reply = command(json!({ "command": "list_sources" }));
+    assert_eq!(reply.result, Some(json!([])));
+
+    let reply = command(json!({ "command": "reboot" }));
+    assert!(!reply.success);
+    drop(admin);
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+    admin_thread.unwrap().join().unwrap();
+    assert!(!Path::new("/tmp/metrics_daemon_admin_8").exists());
+}
@@ -0,0 +1,185 @@
+// (c) 2017 KAI OS TECHNOLOGIES (HONG KONG) LIMITED All rights reserved. This
+// file or any portion thereof may not be reproduced or used in any manner
+// whatsoever without the express written permission of KAI OS TECHNOLOGIES
+// (HONG KONG) LIMITED. KaiOS is the trademark of KAI OS TECHNOLOGIES (HONG KONG)
+// LIMITED or its affiliate company and may be registered in some jurisdictions.
+// All other trademarks are the property of their respective owners.
+
+/// Accumulates the NC counters and HI byte totals of each source, to relay
+/// one summary per source and window instead of every payload.
+use chrono::{Timelike, Utc};
+use frame_messages::ClientPayload;
+use serde_json::{self, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// The totals of a source over a window.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct CounterSummary {
+    pub kind: String, // Always "CounterSummary".
+    pub source: String,
+    pub start: String, // Window start, in the DT format.
+    pub end: String,   // Window end, in the DT format.
+    pub payloads: u64, // The number of payloads with counters.
+    #[serde(flatten)]
+    pub totals: BTreeMap<String, u64>, // The NCn and HIn totals.
+}
+
+// "NC1" to "NC9", "HI1" and "HI2".
+fn is_counter(field: &str) -> bool {
+    (field.starts_with("NC") || field.starts_with("HI"))
+        && field.len() > 2
+        && field[2..].chars().all(|c| c.is_ascii_digit())
+}
+
+fn now() -> String {
+    // We don't want sub-second precision
+    format!("{:?}", Utc::now().with_nanosecond(0).unwrap())
+}
+
+#[derive(Default)]
+struct Totals {
+    payloads: u64,
+    totals: BTreeMap<String, u64>,
+}
+
+pub struct Aggregator {
+    window: Option<Duration>, // None when aggregation is disabled.
+    window_start: String,
+    next_flush: Instant,
+    sources: HashMap<String, Totals>,
+}
+
+impl Aggregator {
+    /// A window of 0 seconds disables aggregation.
+    pub fn new(window: u64) -> Self {
+        let window = if window == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(window))
+        };
+        Aggregator {
+            window,
+            window_start: now(),
+            next_flush: Instant::now() + window.unwrap_or_default(),
+            sources: HashMap::new(),
+        }
+    }
+
+    /// When the current window ends, if aggregation is enabled.
+    pub fn next_flush(&self) -> Option<Instant> {
+        self.window.map(|_| self.next_flush)
+    }
+
+    /// Takes the counters out of the payload, and returns what's left of it
+    /// if it still carries other data, like NE events.
+    pub fn add(&mut self, source: &str, payload: ClientPayload) -> Option<ClientPayload> {
+        if self.window.is_none() {
+            return Some(payload);
+        }
+
+        let mut fields = match serde_json::to_value(&payload) {
+            Ok(Value::Object(fields)) => fields,
+            _ => return Some(payload),
+        };
+        let counters: Vec<String> = fields
+            .iter()
+            .filter(|&(field, value)| is_counter(field) && !value.is_null())
+            .map(|(field, _)| field.clone())
+            .collect();
+        if counters.is_empty() {
+            return Some(payload);
+        }
+
+        let totals = self.sources.entry(source.to_owned()).or_default();
+        totals.payloads += 1;
+        for field in counters {
+            let value = fields.remove(&field).and_then(|v| v.as_u64()).unwrap_or(0);
+            let total = totals.totals.entry(field).or_insert(0);
+            *total = total.saturating_add(value);
+        }
+
+        let has_data = fields
+            .iter()
+            .any(|(field, value)| field != "Name" && field != "DT" && !value.is_null());
+        if has_data {
+            serde_json::from_value(Value::Object(fields)).ok()
+        } else {
+            None
+        }
+    }
+
+    /// Ends the current window, returning the summary of each source.
+    pub fn flush(&mut self) -> Vec<CounterSummary> {
+        let end = now();
+        let start = ::std::mem::replace(&mut self.window_start, end.clone());
+        if let Some(window) = self.window {
+            self.next_flush = Instant::now() + window;
+        }
+
+        let mut summaries: Vec<CounterSummary> = self
+            .sources
+            .drain()
+            .map(|(source, totals)| CounterSummary {
+                kind: "CounterSummary".into(),
+                source,
+                start: start.clone(),
+                end: end.clone(),
+                payloads: totals.payloads,
+                totals: totals.totals,
+            })
+            .collect();
+        summaries.sort_by(|a, b| a.source.cmp(&b.source));
+        summaries
+    }
+}
+
+#[test]
+fn aggregate_counters() {
+    let mut aggregator = Aggregator::new(60);
+    assert!(aggregator.next_flush().is_some());
+
+    let payload = |value: Value| -> ClientPayload { serde_json::from_value(value).unwrap() };
+
+    // Counter only payloads are absorbed.
+    let res = aggregator.add("ril", payload(json!({ "Name": "NE11", "NC1": 1, "HI1": 100 })));
+    assert!(res.is_none());
+    let res = aggregator.add("ril", payload(json!({ "Name": "NE11", "NC1": 2, "NC4": 1 })));
+    assert!(res.is_none());
+    let res = aggregator.add("data", payload(json!({ "Name": "NE11", "HI2": 5 })));
+    assert!(res.is_none());
+
+    // Events go through right away, without their counters.
+    let res = aggregator.add("ril", payload(json!({ "Name": "NE5", "NE5": "drop", "NC4": 1 })));
+    let fields = serde_json::to_value(res.unwrap()).unwrap();
+    assert_eq!(fields["NE5"], json!("drop"));
+    assert!(fields.get("NC4").is_none());
+
+    let res = aggregator.add("ril", payload(json!({ "Name": "NE1", "RI1": 45 })));
+    assert!(res.is_some());
+
+    let summaries = aggregator.flush();
+    assert_eq!(summaries.len(), 2);
+    assert_eq!(summaries[0].source, "data");
+    assert_eq!(summaries[0].payloads, 1);
+    assert_eq!(summaries[1].source, "ril");
+    assert_eq!(summaries[1].payloads, 3);
+    let totals = serde_json::to_value(&summaries[1]).unwrap();
+    assert_eq!(totals["NC1"], json!(3));
+    assert_eq!(totals["NC4"], json!(2));
+    assert_eq!(totals["HI1"], json!(100));
+    assert_eq!(totals["kind"], json!("CounterSummary"));
+
+    assert!(aggregator.flush().is_empty());
+}
+
+#[test]
+fn aggregation_disabled() {
+    let mut aggregator = Aggregator::new(0);
+    assert!(aggregator.next_flush().is_none());
+
+    let payload: ClientPayload =
+        serde_json::from_value(json!({ "Name": "NE11", "NC1": 1 })).unwrap();
+    let res = aggregator.add("ril", payload).unwrap();
+    assert_eq!(serde_json::to_value(&res).unwrap()["NC1"], json!(1));
+}
@@ -0,0 +1,72 @@
+// (c) 2017 KAI OS TECHNOLOGIES (HONG KONG) LIMITED All rights reserved. This
+// file or any portion thereof may not be reproduced or used in any manner
+// whatsoever without the express written permission of KAI OS TECHNOLOGIES
+// (HONG KONG) LIMITED. KaiOS is the trademark of KAI OS TECHNOLOGIES (HONG KONG)
+// LIMITED or its affiliate company and may be registered in some jurisdictions.
+// All other trademarks are the property of their respective owners.
+
+/// Command line client for the admin socket of the metrics daemon.
+extern crate metrics_daemon;
+#[macro_use]
+extern crate serde_json;
+
+use metrics_daemon::admin::AdminReply;
+use metrics_daemon::frame::Frame;
+use serde_json::Value;
+use std::env;
+use std::os::unix::net::UnixStream;
+use std::process;
+
+static USAGE: &str = "Usage: metrics_admin <admin socket> <command> [argument]
+
+Commands:
+  list_sources             List the connected clients.
+  queue_stats              Show the queue, spool and relay state.
+  get_filter               Show the current filter.
+  set_filter <json>        Set the filter, eg. '{\"NC\": 1, \"ND\": 2, \"NE\": 3}'.
+  flush_queue              Flush the aggregates and relay the buffered items.
+  drop_source <source>     Close the connection of a client.
+  set_log_level <level>    Change the log level (error, warn, info, debug, trace).";
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn build_command(command: &str, argument: Option<String>) -> Value {
+    let argument = || argument.clone().unwrap_or_else(|| fail(USAGE));
+    match command {
+        "set_filter" => {
+            let filter: Value = serde_json::from_str(&argument())
+                .unwrap_or_else(|err| fail(&format!("Invalid filter: {}", err)));
+            json!({ "command": command, "filter": filter })
+        }
+        "drop_source" => json!({ "command": command, "source": argument() }),
+        "set_log_level" => json!({ "command": command, "level": argument() }),
+        _ => json!({ "command": command }),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 {
+        fail(USAGE);
+    }
+    let command = build_command(&args[1], args.get(2).cloned());
+
+    let mut stream = UnixStream::connect(&args[0])
+        .unwrap_or_else(|err| fail(&format!("Can't connect to {}: {}", args[0], err)));
+    Frame::from_json(&command)
+        .write_to(&mut stream)
+        .unwrap_or_else(|err| fail(&format!("Failed to send the command: {:?}", err)));
+    let reply: AdminReply = Frame::read_from(&mut stream)
+        .and_then(|frame| frame.deserialize())
+        .unwrap_or_else(|err| fail(&format!("Invalid reply: {:?}", err)));
+
+    match reply.result {
+        Some(ref result) if reply.success => {
+            println!("{}", serde_json::to_string_pretty(result).unwrap())
+        }
+        _ => fail(&reply.error.unwrap_or_else(|| "Command failed".into())),
+    }
+}
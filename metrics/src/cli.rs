@@ -0,0 +1,149 @@
+// (c) 2017 KAI OS TECHNOLOGIES (HONG KONG) LIMITED All rights reserved. This
+// file or any portion thereof may not be reproduced or used in any manner
+// whatsoever without the express written permission of KAI OS TECHNOLOGIES
+// (HONG KONG) LIMITED. KaiOS is the trademark of KAI OS TECHNOLOGIES (HONG KONG)
+// LIMITED or its affiliate company and may be registered in some jurisdictions.
+// All other trademarks are the property of their respective owners.
+
+/// Command line options of the metrics_daemon binary.
+use config::Config;
+
+pub static USAGE: &str = "Usage: metrics_daemon [options] [config file]
+
+Options:
+  --config <path>       The JSON config file, ./config.json by default.
+  --socket <path>       Listen for clients on this socket instead of socket_path.
+  --relay-port <port>   Relay to this port instead of relay_port.
+  --verbose             Display debug logs.
+  --check-config        Check the configuration and exit.
+  --foreground          Stay attached to the terminal, which is the default.
+  --daemonize           Detach from the terminal.
+  --pidfile <path>      Write the daemon pid to this file.
+  --version             Print the version and exit.
+  --help                Print this help and exit.";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub config: String,          // The path of the JSON config file.
+    pub socket: Option<String>,  // Overrides socket_path.
+    pub relay_port: Option<u16>, // Overrides relay_port.
+    pub verbose: bool,           // Overrides verbose when set.
+    pub check_config: bool,      // True to only check the configuration.
+    pub daemonize: bool,         // True to detach from the terminal.
+    pub pidfile: Option<String>, // Where to write the daemon pid.
+    pub version: bool,           // True to only print the version.
+    pub help: bool,              // True to only print the usage.
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            config: "./config.json".into(),
+            socket: None,
+            relay_port: None,
+            verbose: false,
+            check_config: false,
+            daemonize: false,
+            pidfile: None,
+            version: false,
+            help: false,
+        }
+    }
+}
+
+impl Options {
+    /// Parses the arguments, without the program name. A single positional
+    /// argument is the config file, as in previous versions.
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut positional = false;
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {}", name))
+            };
+            match arg.as_str() {
+                "--config" => options.config = value(&arg)?,
+                "--socket" => options.socket = Some(value(&arg)?),
+                "--relay-port" => {
+                    let port = value(&arg)?;
+                    let port = port
+                        .parse()
+                        .map_err(|_| format!("Invalid port: {}", port))?;
+                    options.relay_port = Some(port);
+                }
+                "--pidfile" => options.pidfile = Some(value(&arg)?),
+                "--verbose" => options.verbose = true,
+                "--check-config" => options.check_config = true,
+                "--foreground" => options.daemonize = false,
+                "--daemonize" => options.daemonize = true,
+                "--version" => options.version = true,
+                "--help" | "-h" => options.help = true,
+                _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+                _ if !positional => {
+                    options.config = arg;
+                    positional = true;
+                }
+                _ => return Err(format!("Unexpected argument: {}", arg)),
+            }
+        }
+        Ok(options)
+    }
+
+    /// Applies the overrides to a loaded configuration, and checks it again.
+    pub fn apply(&self, config: &mut Config) -> ::config::Result<()> {
+        if let Some(ref socket) = self.socket {
+            config.socket_path = socket.clone();
+        }
+        if let Some(port) = self.relay_port {
+            config.relay_port = port;
+        }
+        if self.verbose {
+            config.verbose = true;
+        }
+        config.check()
+    }
+}
+
+#[test]
+fn parse_options() {
+    let parse = |args: &[&str]| Options::parse(args.iter().map(|arg| arg.to_string()));
+
+    assert_eq!(parse(&[]).unwrap(), Options::default());
+    assert_eq!(
+        parse(&["/etc/metrics.json"]).unwrap().config,
+        "/etc/metrics.json"
+    );
+
+    let options = parse(&[
+        "--config",
+        "/etc/metrics.json",
+        "--socket",
+        "/tmp/metricsd",
+        "--relay-port",
+        "4000",
+        "--verbose",
+        "--daemonize",
+        "--pidfile",
+        "/run/metricsd.pid",
+    ])
+    .unwrap();
+    assert_eq!(options.config, "/etc/metrics.json");
+    assert!(options.verbose && options.daemonize && !options.check_config);
+    assert_eq!(options.pidfile, Some("/run/metricsd.pid".into()));
+
+    let mut config = Config::default();
+    options.apply(&mut config).unwrap();
+    assert_eq!(config.socket_path, "/tmp/metricsd");
+    assert_eq!(config.relay_port, 4000);
+    assert!(config.verbose);
+
+    assert!(parse(&["--relay-port", "x"]).is_err());
+    assert!(parse(&["--relay-port"]).is_err());
+    assert!(parse(&["--nope"]).is_err());
+    assert!(parse(&["a.json", "b.json"]).is_err());
+    assert!(parse(&["--check-config"]).unwrap().check_config);
+    assert_eq!(parse(&["--foreground"]).unwrap(), Options::default());
+    let options = parse(&["--relay-port", "0"]).unwrap();
+    assert!(options.apply(&mut Config::default()).is_err());
+}
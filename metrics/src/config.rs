@@ -5,32 +5,449 @@
 // LIMITED or its affiliate company and may be registered in some jurisdictions.
 // All other trademarks are the property of their respective owners.
 
-use serde_json;
+use frame::DEFAULT_MAX_FRAME_SIZE;
+use serde_json::{self, Map, Value};
+use std::env;
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use privacy::Policies;
+use std::collections::BTreeMap;
+use toml;
+use validation::{Profile, Rules};
 
-#[derive(Deserialize, Clone)]
+error_chain!{
+    errors {
+        Unreadable(path: String, reason: String) {
+            description("Can't read the config file")
+            display("Can't read {}: {}", path, reason)
+        }
+
+        InvalidValue(reason: String) {
+            description("Invalid config value")
+            display("Invalid config value: {}", reason)
+        }
+    }
+
+    foreign_links {
+        Json(::serde_json::Error);
+        Toml(::toml::de::Error);
+    }
+}
+
+// Missing properties get the value of Config::default().
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
 pub struct Config {
     pub socket_path: String, // The path to the socket we listen on.
     pub mqtt_host: String,   // The url of the mqtt server.
     pub buffer_size: usize,  // The number of events we keep.
     pub relay_port: u16,     // The socket port we relay packets to.
     pub verbose: bool,       // True to display debug logs.
+    pub max_frame_size: usize, // The maximum payload size of client frames.
+    pub mqtt_enabled: bool, // True to also publish relayed items to the mqtt server.
+    pub mqtt_client_id: String, // The client identifier sent in CONNECT.
+    pub mqtt_payload_topic: String, // The topic used for client payloads.
+    pub mqtt_ack_topic: String, // The topic used for filter acks.
+    pub mqtt_keep_alive: u16, // The keepalive interval, in seconds.
+    pub relay_acks: bool, // True if the relay consumer acknowledges the records it gets.
+    pub spool_path: Option<String>, // The directory used to spool events while the relay is down.
+    pub spool_max_bytes: u64, // The maximum size of the spool on disk.
+    pub spool_max_records: usize, // The maximum number of spooled events.
+    pub spool_segment_size: u64, // The size of each spool segment file.
+    pub enforce_filter: bool, // True to drop and strip what the FilterFrame masks off.
+    pub strict_validation: bool, // True to check the ranges and formats of all the fields.
+    pub validation: Rules, // Replace the default validation rules of some fields.
+    pub profiles: BTreeMap<String, Profile>, // The operator profiles, by name.
+    pub profile: Option<String>, // The profile used when LI1 doesn't select one.
+    pub privacy: Policies, // How to scrub personal data fields before relaying them.
+    pub privacy_salt_path: Option<String>, // Where the device-local hashing salt is kept.
+    pub aggregation_window: u64, // Seconds over which counters are summed, 0 to relay them as is.
+    pub rollup_window: u64, // Seconds over which cell KPIs are rolled up, 0 to relay them as is.
+    pub admin_socket_path: Option<String>, // The path of the admin socket, if any.
+    pub stats_port: Option<u16>, // The localhost port serving the daemon stats, if any.
+    pub stats_socket_path: Option<String>, // The path of the socket serving the daemon stats, if any.
+    pub shutdown_timeout: u64, // Seconds we wait for the threads to finish their work on shutdown.
+    pub file_sink_path: Option<String>, // The file items are also appended to, if any.
+    pub sink_events: BTreeMap<String, Vec<String>>, // The event names each sink gets, all if missing.
+    pub relay_address: Option<String>, // tcp://host:port or unix:///path, replaces relay_port.
+    pub relay_uid: Option<u32>, // The uid of a Unix socket consumer, ours by default.
+}
+
+/// Where the relay consumer listens.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RelayAddress {
+    Tcp(String), // host:port
+    Unix(PathBuf),
+}
+
+impl fmt::Display for RelayAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RelayAddress::Tcp(ref address) => write!(f, "tcp://{}", address),
+            RelayAddress::Unix(ref path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+/// The names of the sinks, as used in sink_events.
+pub const SINKS: [&str; 3] = ["relay", "mqtt", "file"];
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            socket_path: "/dev/socket/metricsd_socket".into(),
+            mqtt_host: "localhost:1883".into(),
+            buffer_size: 10,
+            relay_port: 12345,
+            verbose: false,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            mqtt_enabled: false,
+            mqtt_client_id: "metrics_daemon".into(),
+            mqtt_payload_topic: "metrics/payload".into(),
+            mqtt_ack_topic: "metrics/filter_ack".into(),
+            mqtt_keep_alive: 60,
+            relay_acks: false,
+            spool_path: None,
+            spool_max_bytes: 1024 * 1024,
+            spool_max_records: 10_000,
+            spool_segment_size: 64 * 1024,
+            enforce_filter: false,
+            strict_validation: false,
+            validation: Rules::new(),
+            profiles: BTreeMap::new(),
+            profile: None,
+            privacy: Policies::new(),
+            privacy_salt_path: None,
+            aggregation_window: 0,
+            rollup_window: 0,
+            admin_socket_path: None,
+            stats_port: None,
+            stats_socket_path: None,
+            shutdown_timeout: 5,
+            file_sink_path: None,
+            sink_events: BTreeMap::new(),
+            relay_address: None,
+            relay_uid: None,
+        }
+    }
+}
+
+// Environment variables overriding config properties, eg. METRICSD_RELAY_PORT.
+const ENV_PREFIX: &str = "METRICSD_";
+
+// The section of the TOML file holding our properties.
+const TOML_SECTION: &str = "metrics";
+
+// Properties replace the ones of the previous layers.
+fn merge(config: &mut Map<String, Value>, layer: Map<String, Value>) {
+    for (name, value) in layer {
+        config.insert(name, value);
+    }
+}
+
+// Environment values are strings, so they are parsed as JSON unless the
+// property is a string.
+fn env_value(default: Option<&Value>, raw: &str) -> Value {
+    match default {
+        Some(&Value::String(_)) => Value::String(raw.to_owned()),
+        _ => serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_owned())),
+    }
+}
+
+fn read_file(path: &Path) -> Result<String> {
+    let mut source = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut source))
+        .map_err(|err| ErrorKind::Unreadable(path.display().to_string(), err.to_string()))?;
+    Ok(source)
+}
+
+fn read_toml(path: &Path) -> Result<Map<String, Value>> {
+    let mut table: BTreeMap<String, Value> = toml::from_str(&read_file(path)?)?;
+    match table.remove(TOML_SECTION) {
+        Some(Value::Object(section)) => Ok(section),
+        Some(_) => bail!(ErrorKind::InvalidValue(format!(
+            "[{}] is not a table in {}",
+            TOML_SECTION,
+            path.display()
+        ))),
+        None => Ok(Map::new()),
+    }
 }
 
 impl Config {
-    pub fn load(path: &PathBuf) -> Self {
-        let mut file = File::open(path).expect("Can't open config file");
-        let mut source = String::new();
-        file.read_to_string(&mut source)
-            .expect("Unable to read config file");
-        serde_json::from_str(&source).expect("Invalid config file")
+    /// Loads the JSON config file at `path`, then the properties of the TOML
+    /// file with the same name and a .toml extension if there is one, then
+    /// the environment overrides. Missing properties get their default value.
+    pub fn load(path: &Path) -> Result<Self> {
+        let toml = path.with_extension("toml");
+        let toml = if toml.exists() && toml != path {
+            Some(toml)
+        } else {
+            None
+        };
+        Config::load_layers(path, toml.as_deref(), env::vars())
+    }
+
+    fn load_layers<I>(json: &Path, toml: Option<&Path>, vars: I) -> Result<Self>
+    where
+        I: Iterator<Item = (String, String)>,
+    {
+        let mut config = Map::new();
+        merge(&mut config, serde_json::from_str(&read_file(json)?)?);
+        if let Some(toml) = toml {
+            merge(&mut config, read_toml(toml)?);
+        }
+
+        let defaults = match serde_json::to_value(Config::default())? {
+            Value::Object(defaults) => defaults,
+            _ => Map::new(),
+        };
+        for (name, raw) in vars {
+            if !name.starts_with(ENV_PREFIX) {
+                continue;
+            }
+            let property = name[ENV_PREFIX.len()..].to_lowercase();
+            if !defaults.contains_key(&property) {
+                warn!("Ignoring {}, there is no {} property", name, property);
+                continue;
+            }
+            let value = env_value(defaults.get(&property), &raw);
+            config.insert(property, value);
+        }
+
+        for name in config.keys() {
+            if !defaults.contains_key(name) {
+                warn!("Unknown config property: {}", name);
+            }
+        }
+
+        let config: Config = serde_json::from_value(Value::Object(config))?;
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Checks the values that are valid for serde, but not for the daemon.
+    pub fn check(&self) -> Result<()> {
+        let invalid = |reason: &str| Err(ErrorKind::InvalidValue(reason.into()).into());
+
+        if self.socket_path.is_empty() {
+            return invalid("socket_path is empty");
+        }
+        if self.buffer_size == 0 {
+            return invalid("buffer_size needs to be at least 1");
+        }
+        if self.relay_address.is_none() && self.relay_port == 0 {
+            return invalid("relay_port can't be 0");
+        }
+        if self.max_frame_size == 0 {
+            return invalid("max_frame_size needs to be at least 1");
+        }
+        if self.mqtt_enabled && self.mqtt_host.is_empty() {
+            return invalid("mqtt_host is empty");
+        }
+        if self.spool_path.is_some()
+            && (self.spool_max_bytes == 0 || self.spool_max_records == 0 || self.spool_segment_size == 0)
+        {
+            return invalid("spool_max_bytes, spool_max_records and spool_segment_size need to be at least 1");
+        }
+        if self.stats_port == Some(0) {
+            return invalid("stats_port can't be 0");
+        }
+        if let Some(ref profile) = self.profile {
+            if !self.profiles.contains_key(profile) {
+                return invalid(&format!("profile {} is not in profiles", profile));
+            }
+        }
+        self.relay_address()?;
+        if let Some(name) = self.sink_events.keys().find(|name| !SINKS.contains(&name.as_str())) {
+            return invalid(&format!("sink_events has an unknown sink {}", name));
+        }
+        let paths = [&self.admin_socket_path, &self.stats_socket_path];
+        if paths.iter().any(|path| path.as_ref() == Some(&self.socket_path))
+            || (self.admin_socket_path.is_some() && self.admin_socket_path == self.stats_socket_path)
+        {
+            return invalid("socket_path, admin_socket_path and stats_socket_path need to differ");
+        }
+        Ok(())
+    }
+
+    /// The relay address, which is the relay_port on localhost unless
+    /// relay_address is set.
+    pub fn relay_address(&self) -> Result<RelayAddress> {
+        let address = match self.relay_address {
+            Some(ref address) => address,
+            None => return Ok(RelayAddress::Tcp(format!("127.0.0.1:{}", self.relay_port))),
+        };
+        let invalid = || ErrorKind::InvalidValue(format!("relay_address {} is invalid", address));
+
+        if let Some(path) = address.strip_prefix("unix://") {
+            // A relative path would depend on the daemon working directory.
+            if !path.starts_with('/') {
+                bail!(invalid());
+            }
+            return Ok(RelayAddress::Unix(path.into()));
+        }
+        let host = address.strip_prefix("tcp://").ok_or_else(invalid)?;
+        let valid = match host.rsplit_once(':') {
+            Some((name, port)) => !name.is_empty() && port.parse::<u16>().is_ok_and(|port| port != 0),
+            None => false,
+        };
+        if !valid {
+            bail!(invalid());
+        }
+        Ok(RelayAddress::Tcp(host.to_owned()))
+    }
+
+    /// The names of the properties that differ in `other`.
+    pub fn changes(&self, other: &Config) -> Vec<String> {
+        let (current, other) = match (serde_json::to_value(self), serde_json::to_value(other)) {
+            (Ok(Value::Object(current)), Ok(Value::Object(other))) => (current, other),
+            _ => return vec![],
+        };
+        current
+            .iter()
+            .filter(|&(name, value)| other.get(name) != Some(value))
+            .map(|(name, _)| name.clone())
+            .collect()
     }
 }
 
 #[test]
 fn load_config() {
-    let config = Config::load(&PathBuf::from("./config.json.sample"));
+    let config = Config::load(Path::new("./config.json.sample")).unwrap();
     assert_eq!(config.mqtt_host, "localhost:12345");
+    assert!(!config.mqtt_enabled);
+    assert_eq!(config.mqtt_payload_topic, "metrics/payload");
+}
+
+#[test]
+fn config_changes() {
+    let config = Config::default();
+    let other = Config {
+        buffer_size: 20,
+        verbose: true,
+        ..Config::default()
+    };
+    assert_eq!(config.changes(&other), vec!["buffer_size", "verbose"]);
+    assert!(config.changes(&config.clone()).is_empty());
+
+    assert!(config.check().is_ok());
+    let invalid = Config {
+        buffer_size: 0,
+        ..Config::default()
+    };
+    assert!(invalid.check().is_err());
+    let mut invalid = Config::default();
+    invalid.sink_events.insert("printer".into(), vec![]);
+    assert!(invalid.check().is_err());
+    let invalid = Config {
+        spool_path: Some("/tmp/spool".into()),
+        spool_max_bytes: 0,
+        ..Config::default()
+    };
+    assert!(invalid.check().is_err());
+
+    // relay_port is not used with a relay_address.
+    let valid = Config {
+        relay_port: 0,
+        relay_address: Some("unix:///tmp/relay".into()),
+        ..Config::default()
+    };
+    assert!(valid.check().is_ok());
+}
+
+#[test]
+fn relay_addresses() {
+    let address = |value: &str| {
+        Config {
+            relay_address: Some(value.into()),
+            ..Config::default()
+        }
+        .relay_address()
+    };
+
+    assert_eq!(
+        Config::default().relay_address().unwrap(),
+        RelayAddress::Tcp("127.0.0.1:12345".into())
+    );
+    assert_eq!(
+        address("tcp://localhost:4000").unwrap(),
+        RelayAddress::Tcp("localhost:4000".into())
+    );
+    assert_eq!(
+        address("unix:///dev/socket/metrics_relay").unwrap(),
+        RelayAddress::Unix("/dev/socket/metrics_relay".into())
+    );
+    assert_eq!(
+        address("unix:///tmp/relay").unwrap().to_string(),
+        "unix:///tmp/relay"
+    );
+    for value in &["localhost:4000", "tcp://localhost", "tcp://:4000", "tcp://host:0", "unix://", "unix://relay", "udp://host:1"] {
+        assert!(address(value).is_err(), "{} is valid", value);
+    }
+}
+
+#[test]
+fn layered_config() {
+    use std::fs;
+
+    let base =
+        ::std::env::temp_dir().join(format!("metrics_layered_config_{}", ::std::process::id()));
+    let json = &base.with_extension("json");
+    let toml = &base.with_extension("toml");
+    fs::write(json, r#"{ "relay_port": 1000, "buffer_size": 50, "unknown": 1 }"#).unwrap();
+    fs::write(
+        toml,
+        r#"
+[general]
+port = 8081
+
+[metrics]
+relay_port = 2000
+socket_path = "/tmp/metricsd_toml"
+
+[metrics.privacy]
+DI1 = { policy = "drop" }
+"#,
+    )
+    .unwrap();
+
+    let vars = vec![
+        ("METRICSD_RELAY_PORT".to_owned(), "3000".to_owned()),
+        ("METRICSD_MQTT_CLIENT_ID".to_owned(), "1234".to_owned()),
+        ("METRICSD_STATS_PORT".to_owned(), "9100".to_owned()),
+        ("METRICSD_NOPE".to_owned(), "1".to_owned()),
+        ("HOME".to_owned(), "/root".to_owned()),
+    ];
+    let config = Config::load_layers(json, Some(toml), vars.into_iter()).unwrap();
+    assert_eq!(config.buffer_size, 50);
+    assert_eq!(config.socket_path, "/tmp/metricsd_toml");
+    assert_eq!(config.relay_port, 3000);
+    assert_eq!(config.mqtt_client_id, "1234");
+    assert_eq!(config.stats_port, Some(9100));
+    assert!(config.privacy.contains_key("DI1"));
+    // Defaults fill in the rest.
+    assert_eq!(config.mqtt_host, "localhost:1883");
+
+    // Semantic errors are reported.
+    let vars = vec![("METRICSD_BUFFER_SIZE".to_owned(), "0".to_owned())];
+    match Config::load_layers(json, None, vars.into_iter()) {
+        Err(Error(ErrorKind::InvalidValue(_), _)) => {}
+        other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+    }
+    let vars = vec![("METRICSD_BUFFER_SIZE".to_owned(), "many".to_owned())];
+    match Config::load_layers(json, None, vars.into_iter()) {
+        Err(Error(ErrorKind::Json(_), _)) => {}
+        other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+    }
+    let _ = fs::remove_file(json);
+    let _ = fs::remove_file(toml);
+
+    match Config::load(Path::new("/tmp/metrics_no_such_config.json")) {
+        Err(Error(ErrorKind::Unreadable(..), _)) => {}
+        other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+    }
 }
@@ -0,0 +1,168 @@
+// (c) 2017 KAI OS TECHNOLOGIES (HONG KONG) LIMITED All rights reserved. This
+// file or any portion thereof may not be reproduced or used in any manner
+// whatsoever without the express written permission of KAI OS TECHNOLOGIES
+// (HONG KONG) LIMITED. KaiOS is the trademark of KAI OS TECHNOLOGIES (HONG KONG)
+// LIMITED or its affiliate company and may be registered in some jurisdictions.
+// All other trademarks are the property of their respective owners.
+
+/// File logger: appends items to a local file as JSON lines, from its own
+/// thread so that a slow or full disk doesn't hold the queue back.
+use serde::Serialize;
+use serde_json;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How long we wait before writing again after a failure.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+enum Command {
+    Log(Vec<u8>),
+    Shutdown,
+}
+
+struct LoggerState {
+    path: String,
+    max_pending: usize,
+    file: Option<File>,
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl LoggerState {
+    fn enqueue(&mut self, line: Vec<u8>) {
+        if self.pending.len() >= self.max_pending {
+            info!("File logger queue overflow, removing element");
+            self.pending.pop_front();
+        }
+        self.pending.push_back(line);
+    }
+
+    // The file is opened again after a failed write.
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        let mut file = match self.file.take() {
+            Some(file) => file,
+            None => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?,
+        };
+        file.write_all(line)?;
+        self.file = Some(file);
+        Ok(())
+    }
+
+    // Writes the pending lines, oldest first, returning false if we have to
+    // try again later.
+    fn flush(&mut self) -> bool {
+        while let Some(line) = self.pending.pop_front() {
+            if let Err(err) = self.write(&line) {
+                error!("Failed to write to {}: {}", self.path, err);
+                self.pending.push_front(line);
+                return false;
+            }
+        }
+        true
+    }
+
+    fn run(&mut self, commands: Receiver<Command>) {
+        let mut failed = false;
+        loop {
+            let command = if failed {
+                match commands.recv_timeout(RETRY_DELAY) {
+                    Ok(command) => Some(command),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => Some(Command::Shutdown),
+                }
+            } else {
+                Some(commands.recv().unwrap_or(Command::Shutdown))
+            };
+            match command {
+                Some(Command::Log(line)) => self.enqueue(line),
+                Some(Command::Shutdown) => {
+                    if !self.flush() {
+                        info!("Dropping {} unwritten lines", self.pending.len());
+                    }
+                    return;
+                }
+                None => {}
+            }
+            failed = !self.flush();
+        }
+    }
+}
+
+/// Handle to the file logger thread.
+pub struct FileLogger {
+    commands: Sender<Command>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FileLogger {
+    /// Starts appending to `path`, keeping up to `max_pending` lines while
+    /// the file can't be written.
+    pub fn start(path: &str, max_pending: usize) -> Self {
+        let (tx, rx) = channel::<Command>();
+        let mut state = LoggerState {
+            path: path.to_owned(),
+            max_pending,
+            file: None,
+            pending: VecDeque::new(),
+        };
+
+        let thread = thread::Builder::new()
+            .name("file logger".to_owned())
+            .spawn(move || state.run(rx))
+            .expect("Failed to create file logger thread");
+
+        FileLogger {
+            commands: tx,
+            thread: Some(thread),
+        }
+    }
+
+    /// Queues a JSON serialized item to be appended to the file.
+    pub fn log<T: Serialize>(&self, item: &T) {
+        let mut line = serde_json::to_vec(item).unwrap();
+        line.push(b'\n');
+        if self.commands.send(Command::Log(line)).is_err() {
+            error!("File logger thread is gone");
+        }
+    }
+
+    /// Writes what it can and waits for the logger thread to exit.
+    pub fn shutdown(mut self) {
+        let _ = self.commands.send(Command::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[test]
+fn test_file_logger_retry() {
+    use std::fs;
+
+    let dir = ::std::env::temp_dir().join(format!("metrics_file_logger_{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let path = dir.join("events.log");
+
+    // The directory doesn't exist yet, so the lines are kept, up to the limit.
+    let logger = FileLogger::start(path.to_str().unwrap(), 2);
+    for name in &["NE1", "NE2", "NE3"] {
+        logger.log(&json!({ "Name": name }));
+    }
+    thread::sleep(Duration::from_millis(200));
+    fs::create_dir_all(&dir).unwrap();
+    logger.log(&json!({ "Name": "NE4" }));
+    logger.shutdown();
+
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "{\"Name\":\"NE3\"}\n{\"Name\":\"NE4\"}\n"
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -6,7 +6,12 @@
 // All other trademarks are the property of their respective owners.
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use serde::{Deserialize, Serialize};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_cbor;
 use serde_json::{self, Value};
 use std::io::{Read, Write};
 
@@ -14,12 +19,16 @@ use std::io::{Read, Write};
 pub enum FrameType {
     Invalid,
     Json,
+    Cbor,
+    Deflate, // Deflate compressed Json.
 }
 
 impl From<u8> for FrameType {
     fn from(source: u8) -> Self {
         match source {
             1 => FrameType::Json,
+            2 => FrameType::Cbor,
+            3 => FrameType::Deflate,
             _ => FrameType::Invalid,
         }
     }
@@ -30,6 +39,8 @@ impl Into<u8> for FrameType {
         match self {
             FrameType::Invalid => 0,
             FrameType::Json => 1,
+            FrameType::Cbor => 2,
+            FrameType::Deflate => 3,
         }
     }
 }
@@ -48,6 +59,18 @@ error_chain!{
         }
 
         Json(s: String)
+
+        Cbor(s: String)
+
+        FrameTooLarge(length: usize, max: usize) {
+            description("Frame too large")
+            display("Frame of {} bytes is larger than the maximum of {} bytes", length, max)
+        }
+
+        InflatedTooLarge(max: usize) {
+            description("Inflated frame too large")
+            display("Inflated frame is larger than the maximum of {} bytes", max)
+        }
     }
 
     foreign_links {
@@ -55,28 +78,62 @@ error_chain!{
     }
 }
 
+/// The default upper bound of a frame payload size.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// How many times larger than the maximum frame size a Deflate payload may
+/// get once inflated, to defuse decompression bombs.
+pub const MAX_INFLATE_RATIO: usize = 8;
+
+/// The upper bound of a Deflate frame payload once inflated, with the default
+/// maximum frame size.
+pub const MAX_INFLATED_SIZE: usize = MAX_INFLATE_RATIO * DEFAULT_MAX_FRAME_SIZE;
+
+fn json_from_slice<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    match serde_json::from_slice(data) {
+        Ok(val) => Ok(val),
+        Err(e) => {
+            let json = String::from_utf8(data.to_vec()).unwrap_or("Invalid utf8".to_owned());
+            trace!("deserialize() failed: payload is {} : {}", json, e);
+            Err(ErrorKind::Json(format!("{:?}", e)).into())
+        }
+    }
+}
+
 impl Frame {
     /// Tries to read a Frame from an io:Read implementation.
     pub fn read_from<T: Read>(source: &mut T) -> Result<Frame> {
+        Frame::read_from_with_limit(source, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// Tries to read a Frame whose payload is at most `max_frame_size` bytes long.
+    pub fn read_from_with_limit<T: Read>(source: &mut T, max_frame_size: usize) -> Result<Frame> {
         let mut typ: [u8; 1] = [0; 1];
-        source.read(&mut typ)?;
+        source.read_exact(&mut typ)?;
 
         if FrameType::from(typ[0]) == FrameType::Invalid {
             bail!(ErrorKind::InvalidFrameType(typ[0]));
         }
 
         // Read the frame length as a unsigned 32 bits network order integer.
-        let length = source.read_u32::<BigEndian>()?;
+        let length = source.read_u32::<BigEndian>()? as usize;
+        if length > max_frame_size {
+            bail!(ErrorKind::FrameTooLarge(length, max_frame_size));
+        }
 
-        let mut data = Vec::with_capacity(length as usize);
-        unsafe {
-            data.set_len(length as usize);
+        // Let the buffer grow with the data we actually get.
+        let mut data = vec![];
+        source.take(length as u64).read_to_end(&mut data)?;
+        if data.len() != length {
+            bail!(::std::io::Error::new(
+                ::std::io::ErrorKind::UnexpectedEof,
+                "Truncated frame"
+            ));
         }
 
-        source.read(&mut data)?;
         Ok(Frame {
             typ: FrameType::from(typ[0]),
-            data: data,
+            data,
         })
     }
 
@@ -103,50 +160,145 @@ impl Frame {
         }
     }
 
-    /// Get the Json value from a Json frame.
+    /// Get the Json value from a Json or Cbor frame.
     pub fn json(&self) -> Result<Value> {
-        if self.typ != FrameType::Json {
-            bail!(ErrorKind::InvalidFrameType(self.typ.clone().into()));
-        }
+        self.deserialize()
+    }
 
-        match serde_json::from_slice(&self.data) {
-            Ok(val) => Ok(val),
-            Err(e) => {
-                let json =
-                    String::from_utf8(self.data.clone()).unwrap_or("Invalid utf8".to_owned());
-                debug!("json() failed: payload is {}", json);
-                Err(ErrorKind::Json(format!("{:?}", e)).into())
-            }
-        }
+    /// Decodes the frame payload, whatever its encoding.
+    pub fn deserialize<T>(&self) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        self.deserialize_with_limit(MAX_INFLATED_SIZE)
     }
 
-    pub fn deserialize<'a, T>(&'a self) -> Result<T>
+    /// Decodes the frame payload, failing if a Deflate payload inflates to
+    /// more than `max_inflated_size` bytes.
+    pub fn deserialize_with_limit<T>(&self, max_inflated_size: usize) -> Result<T>
     where
-        T: Deserialize<'a>,
+        T: DeserializeOwned,
     {
-        if self.typ != FrameType::Json {
-            bail!(ErrorKind::InvalidFrameType(self.typ.clone().into()));
+        match self.typ {
+            FrameType::Json => json_from_slice(&self.data),
+            FrameType::Cbor => match serde_cbor::from_slice(&self.data) {
+                Ok(val) => Ok(val),
+                Err(e) => {
+                    trace!("deserialize() failed: {:?} : {}", self.data, e);
+                    Err(ErrorKind::Cbor(format!("{:?}", e)).into())
+                }
+            },
+            FrameType::Deflate => json_from_slice(&self.inflate(max_inflated_size)?),
+            FrameType::Invalid => bail!(ErrorKind::InvalidFrameType(self.typ.into())),
         }
+    }
 
-        match serde_json::from_slice(&self.data) {
-            Ok(val) => Ok(val),
-            Err(e) => {
-                let json =
-                    String::from_utf8(self.data.clone()).unwrap_or("Invalid utf8".to_owned());
-                trace!("deserialize() failed: payload is {} : {}", json, e);
-                Err(ErrorKind::Json(format!("{:?}", e)).into())
-            }
+    // Never inflates more than max_size bytes.
+    fn inflate(&self, max_size: usize) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        DeflateDecoder::new(&self.data[..])
+            .take(max_size as u64 + 1)
+            .read_to_end(&mut data)?;
+        if data.len() > max_size {
+            bail!(ErrorKind::InflatedTooLarge(max_size));
         }
+        Ok(data)
     }
 
     pub fn from_obj<T>(obj: &T) -> Self
     where
         T: Serialize,
     {
-        Frame {
-            typ: FrameType::Json,
-            data: serde_json::to_vec(obj).unwrap(),
+        Frame::encode(obj, FrameType::Json)
+    }
+
+    /// Build a frame of the given type, falling back to Json for invalid types.
+    pub fn encode<T>(obj: &T, typ: FrameType) -> Self
+    where
+        T: Serialize,
+    {
+        match typ {
+            FrameType::Cbor => Frame {
+                typ,
+                data: serde_cbor::to_vec(obj).unwrap(),
+            },
+            FrameType::Deflate => {
+                let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+                serde_json::to_writer(&mut encoder, obj).unwrap();
+                Frame {
+                    typ,
+                    data: encoder.finish().unwrap(),
+                }
+            }
+            FrameType::Json | FrameType::Invalid => Frame {
+                typ: FrameType::Json,
+                data: serde_json::to_vec(obj).unwrap(),
+            },
+        }
+    }
+}
+
+/// Decodes frames incrementally from data received in arbitrary chunks.
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    max_frame_size: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        FrameDecoder::with_max_frame_size(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder::default()
+    }
+
+    /// Creates a decoder rejecting frames whose payload is larger than `max_frame_size`.
+    pub fn with_max_frame_size(max_frame_size: usize) -> Self {
+        FrameDecoder {
+            buffer: vec![],
+            max_frame_size,
+        }
+    }
+
+    /// Adds received data to the decoder.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns true if no partial frame is pending.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Returns the next complete frame, if we received all of it.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>> {
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+
+        let typ = FrameType::from(self.buffer[0]);
+        if typ == FrameType::Invalid {
+            bail!(ErrorKind::InvalidFrameType(self.buffer[0]));
+        }
+
+        if self.buffer.len() < 5 {
+            return Ok(None);
+        }
+        let length = (&self.buffer[1..5]).read_u32::<BigEndian>()? as usize;
+        // Fail before buffering the payload of a frame we will never accept.
+        if length > self.max_frame_size {
+            bail!(ErrorKind::FrameTooLarge(length, self.max_frame_size));
         }
+        if self.buffer.len() < 5 + length {
+            return Ok(None);
+        }
+
+        let data = self.buffer[5..5 + length].to_vec();
+        self.buffer.drain(..5 + length);
+        Ok(Some(Frame { typ, data }))
     }
 }
 
@@ -248,3 +400,158 @@ fn test_deserialize_invalid_data() {
     let init_frame: Result<InitFrame> = frame.deserialize();
     assert_eq!(init_frame.is_err(), true);
 }
+
+#[test]
+fn test_frame_decoder() {
+    let mut encoded = vec![];
+    Frame::from_json(&json!({"source": "test"}))
+        .write_to(&mut encoded)
+        .unwrap();
+    Frame::from_json(&json!({"result": true}))
+        .write_to(&mut encoded)
+        .unwrap();
+
+    // Feed the two frames one byte at a time.
+    let mut decoder = FrameDecoder::new();
+    let mut frames = vec![];
+    for byte in encoded {
+        decoder.feed(&[byte]);
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            frames.push(frame);
+        }
+    }
+    assert!(decoder.is_empty());
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].json().unwrap(), json!({"source": "test"}));
+    assert_eq!(frames[1].json().unwrap(), json!({"result": true}));
+
+    let mut decoder = FrameDecoder::new();
+    decoder.feed(&[42]);
+    assert!(decoder.next_frame().is_err());
+}
+
+#[test]
+fn test_frame_size_limit() {
+    let mut encoded = vec![];
+    Frame::from_json(&json!({"result": true}))
+        .write_to(&mut encoded)
+        .unwrap();
+
+    // 15 bytes of payload.
+    assert!(Frame::read_from_with_limit(&mut &encoded[..], 15).is_ok());
+    match Frame::read_from_with_limit(&mut &encoded[..], 14) {
+        Err(Error(ErrorKind::FrameTooLarge(15, 14), _)) => {}
+        other => panic!("Unexpected result: {:?}", other),
+    }
+
+    // The decoder rejects the frame as soon as it knows its length.
+    let mut decoder = FrameDecoder::with_max_frame_size(14);
+    decoder.feed(&encoded[..5]);
+    match decoder.next_frame() {
+        Err(Error(ErrorKind::FrameTooLarge(15, 14), _)) => {}
+        other => panic!("Unexpected result: {:?}", other),
+    }
+
+    // A 4 GiB length doesn't make us allocate anything.
+    let huge = [1u8, 0xFF, 0xFF, 0xFF, 0xFF];
+    assert!(Frame::read_from(&mut &huge[..]).is_err());
+}
+
+#[test]
+fn test_truncated_frame() {
+    let mut encoded = vec![];
+    Frame::from_json(&json!({"result": true}))
+        .write_to(&mut encoded)
+        .unwrap();
+    encoded.truncate(10);
+
+    match Frame::read_from(&mut &encoded[..]) {
+        Err(Error(ErrorKind::Io(ref err), _))
+            if err.kind() == ::std::io::ErrorKind::UnexpectedEof => {}
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn test_cbor_round_trip() {
+    use frame_messages::{ClientMessage, FULL_MESSAGE, SAMPLE_MESSAGE};
+
+    for input in &[SAMPLE_MESSAGE, FULL_MESSAGE] {
+        let message: ClientMessage = serde_json::from_str(input).unwrap();
+        let expected = serde_json::to_value(vec![&message]).unwrap();
+
+        let mut encoded = vec![];
+        Frame::encode(&vec![message], FrameType::Cbor)
+            .write_to(&mut encoded)
+            .unwrap();
+        assert_eq!(encoded[0], 2);
+
+        let frame = Frame::read_from(&mut &encoded[..]).unwrap();
+        assert_eq!(frame.typ, FrameType::Cbor);
+        let decoded: Vec<ClientMessage> = frame.deserialize().unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), expected);
+        assert_eq!(frame.json().unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_invalid_cbor() {
+    let frame = Frame {
+        typ: FrameType::Cbor,
+        data: vec![0xFF, 0x00],
+    };
+
+    let value = frame.json();
+    match value {
+        Err(Error(ErrorKind::Cbor(_), _)) => {}
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn test_deflate_frame() {
+    use frame_messages::{ClientMessage, FULL_MESSAGE};
+
+    let batch: Vec<ClientMessage> = (0..20)
+        .map(|_| serde_json::from_str(FULL_MESSAGE).unwrap())
+        .collect();
+    let json = Frame::from_obj(&batch);
+    let deflate = Frame::encode(&batch, FrameType::Deflate);
+    assert_eq!(deflate.typ, FrameType::Deflate);
+    assert!(deflate.data.len() * 10 < json.data.len());
+
+    let mut encoded = vec![];
+    deflate.write_to(&mut encoded).unwrap();
+    assert_eq!(encoded[0], 3);
+    let frame = Frame::read_from(&mut &encoded[..]).unwrap();
+    let decoded: Vec<ClientMessage> = frame.deserialize().unwrap();
+    assert_eq!(
+        serde_json::to_value(&decoded).unwrap(),
+        serde_json::to_value(&batch).unwrap()
+    );
+}
+
+#[test]
+fn test_deflate_bomb() {
+    // A few KiB of compressed zeros inflate to more than the limit.
+    let mut encoder = DeflateEncoder::new(vec![], Compression::best());
+    encoder.write_all(&vec![0u8; MAX_INFLATED_SIZE + 1]).unwrap();
+    let frame = Frame {
+        typ: FrameType::Deflate,
+        data: encoder.finish().unwrap(),
+    };
+    assert!(frame.data.len() < DEFAULT_MAX_FRAME_SIZE);
+
+    match frame.json() {
+        Err(Error(ErrorKind::InflatedTooLarge(max), _)) => assert_eq!(max, MAX_INFLATED_SIZE),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+
+    // The limit follows the maximum frame size.
+    let frame = Frame::encode(&vec![0u8; 1000], FrameType::Deflate);
+    assert!(frame.deserialize_with_limit::<Value>(4000).is_ok());
+    match frame.deserialize_with_limit::<Value>(1000) {
+        Err(Error(ErrorKind::InflatedTooLarge(max), _)) => assert_eq!(max, 1000),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
@@ -9,7 +9,18 @@
 
 use chrono::{Timelike, Utc};
 use std::cell::Cell;
+use std::result::Result as StdResult;
 use std::sync::{Arc, Mutex};
+use validation::{ValidationTable, Violation};
+
+// Reply to the init frame of a client.
+#[derive(Deserialize, Serialize, Default)]
+pub struct ReadyFrame {
+    pub ready: bool,
+    pub protocol_version: u32,
+    pub capabilities: Vec<String>,
+    pub compression: Vec<String>,
+}
 
 #[derive(Deserialize, Serialize, Default)]
 pub struct SuccessFrame {
@@ -21,7 +32,11 @@ pub struct SuccessFrame {
 pub struct ErrorFrame {
     pub success: bool,
     pub seq_number: u64,
-    pub error: String,
+    pub error: String, // The code of the first violation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>, // The validation profile used.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<Violation>,
 }
 
 // These frames are relayed from the DC apps to the
@@ -250,40 +265,135 @@ pub struct ClientPayload {
 
 error_chain! {
     errors {
-        InvalidRI6(v: u8) {
-            description("InvalidRI6")
-            display("Invalid RI6 value: : needs to be 40, 5, or 3 but is {}.", v)
+        MissingTimestamp {
+            description("MissingTimestamp")
+            display("The timestamp property is missing.")
+        }
+
+        InvalidTimestamp {
+            description("InvalidTimestamp")
+            display("The timestamp property is not a positive integer.")
         }
 
-        EmptyName {
-            description("EmptyName")
-            display("The payload name is mandatory and can't be empty.")
+        InvalidJSON(s: String) {
+            description("InvalidJSON")
+            display("Invalid message: {}", s)
         }
     }
 }
 
+// The ND mask has one bit per category of data fields, in this order.
+const DATA_CATEGORIES: [&str; 9] = ["DI", "LI", "SI", "TI", "RI", "NI", "OI", "VI", "HI"];
+
+// Returns n for a "<prefix><n>" field name, like 12 for NE12.
+fn field_index(field: &str, prefix: &str) -> Option<u32> {
+    if !field.starts_with(prefix) {
+        return None;
+    }
+    field[prefix.len()..].parse().ok()
+}
+
+fn is_set(mask: u64, bit: u32) -> bool {
+    bit < 64 && mask & (1 << bit) != 0
+}
+
+/// Counts what the daemon removed because of the current FilterFrame.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct FilterStats {
+    pub dropped_events: u64,    // Payloads discarded because of the NE mask.
+    pub stripped_counters: u64, // NC fields removed because of the NC mask.
+    pub stripped_data: u64,     // Data fields removed because of the ND mask.
+}
+
 impl ClientPayload {
-    pub fn validate(mut self) -> Result<Self> {
-        // RI6 range is {40, 5, 3}
-        // We also allow 0 because the modem may not have the actual band value
-        // during registration.
-        if let Some(ri6) = self.RI6 {
-            if ri6 != 0 && ri6 != 3 && ri6 != 5 && ri6 != 40 {
-                bail!(ErrorKind::InvalidRI6(ri6));
+    /// Enforces a filter on this payload. NEn events are discarded when bit n-1
+    /// of `ne` is cleared, NCn counters are removed when bit n-1 of `nc` is
+    /// cleared, and data fields are removed when the bit of their category in
+    /// `nd` is cleared.
+    pub fn apply_filter(self, filter: &FilterFrame, stats: &mut FilterStats) -> Option<Self> {
+        if let Some(index) = field_index(&self.name, "NE") {
+            if index > 0 && !is_set(filter.ne, index - 1) {
+                stats.dropped_events += 1;
+                return None;
+            }
+        }
+
+        let mut fields = match ::serde_json::to_value(&self) {
+            Ok(::serde_json::Value::Object(fields)) => fields,
+            _ => return Some(self),
+        };
+        let before = (stats.stripped_counters, stats.stripped_data);
+        let names: Vec<String> = fields.keys().cloned().collect();
+        for name in names {
+            let allowed = if let Some(index) = field_index(&name, "NC") {
+                let allowed = index == 0 || is_set(filter.nc, index - 1);
+                if !allowed {
+                    stats.stripped_counters += 1;
+                }
+                allowed
+            } else if let Some(bit) = DATA_CATEGORIES
+                .iter()
+                .position(|category| field_index(&name, category).is_some())
+            {
+                let allowed = is_set(filter.nd, bit as u32);
+                if !allowed {
+                    stats.stripped_data += 1;
+                }
+                allowed
+            } else {
+                true
+            };
+            if !allowed {
+                fields.remove(&name);
             }
         }
 
+        if before == (stats.stripped_counters, stats.stripped_data) {
+            return Some(self);
+        }
+        ::serde_json::from_value(::serde_json::Value::Object(fields)).ok()
+    }
+
+    /// The MCC/MNC used to select the validation profile.
+    pub fn mcc_mnc(&self) -> Option<u32> {
+        self.LI1
+    }
+
+    /// Checks the payload against the validation table, reporting all the violations.
+    pub fn validate(mut self, table: &ValidationTable) -> StdResult<Self, Vec<Violation>> {
+        let violations = self.violations(table);
+        if !violations.is_empty() {
+            return Err(violations);
+        }
+        self.set_default_date();
+        Ok(self)
+    }
+
+    /// All the rules of the validation table that the payload breaks.
+    pub fn violations(&self, table: &ValidationTable) -> Vec<Violation> {
+        let mut violations = vec![];
+
         // Name must not be empty.
         if self.name.is_empty() {
-            bail!(ErrorKind::EmptyName);
+            violations.push(Violation {
+                field: "Name".into(),
+                code: "EmptyName".into(),
+                reason: "The payload name is mandatory and can't be empty.".into(),
+            });
         }
 
-        // Make sure that DT is set.
+        if let Ok(::serde_json::Value::Object(fields)) = ::serde_json::to_value(self) {
+            violations.extend(table.check(&fields));
+        }
+        violations
+    }
+
+    /// Makes sure that DT is set, to the current time by default.
+    pub fn set_default_date(&mut self) {
         if self.DT.is_none() {
             // We don't want sub-second precision
             self.DT = Some(format!("{:?}", Utc::now().with_nanosecond(0).unwrap()));
         }
-        Ok(self)
     }
 
     #[cfg(test)]
@@ -327,11 +437,32 @@ pub struct ClientMessage {
     pub payload: ClientPayload,
 }
 
-#[test]
-fn sample_message() {
-    use serde_json;
+impl ClientMessage {
+    /// Returns the seq_number of a record if it is a positive integer.
+    pub fn seq_number_of(record: &::serde_json::Value) -> Option<u64> {
+        match record.get("seq_number").and_then(|seq| seq.as_u64()) {
+            Some(0) | None => None,
+            seq => seq,
+        }
+    }
 
-    let input = r#"{ "seq_number": 1, "timestamp": 1502889770,
+    /// Decodes a single record of a client batch.
+    pub fn from_record(record: ::serde_json::Value) -> Result<Self> {
+        match record.get("timestamp") {
+            None => bail!(ErrorKind::MissingTimestamp),
+            Some(timestamp) => match timestamp.as_u64() {
+                Some(0) | None => bail!(ErrorKind::InvalidTimestamp),
+                Some(_) => {}
+            },
+        }
+
+        ::serde_json::from_value(record)
+            .map_err(|err| ErrorKind::InvalidJSON(err.to_string()).into())
+    }
+}
+
+#[cfg(test)]
+pub const SAMPLE_MESSAGE: &str = r#"{ "seq_number": 1, "timestamp": 1502889770,
     "payload": { "Name":"NE1",
     "RI1":45,
     "RI2":128,
@@ -345,6 +476,99 @@ fn sample_message() {
     "VI2": 240,
     "VI1": "REGISTERED"}}"#;
 
+#[cfg(test)]
+pub const FULL_MESSAGE: &str = r#"{ "seq_number": 1, "timestamp": 1522393388, "payload": {
+        "Name":"NE17",
+
+        "DI1": "abcdefg",
+        "DI2": "0127654",
+        "DI3": "34fe987",
+        "DI4": "kaios_phone_1",
+        "DI5": "2.5_r1",
+        "LI1": 234567,
+        "LI2": 567,
+        "LI3": 56789,
+        "LI4": 1212,
+        "LI5": 122.04,
+        "LI6": 37.3,
+        "LI7": true,
+        "LI8": 1.0,
+        "SI1": 57,
+        "SI2": 31,
+        "SI3": 67,
+        "TI1": 40,
+        "TI2": 43,
+
+        "RI1":37,
+        "RI2":8,
+        "RI3":5,
+        "RI4": 11,
+        "RI5": 0,
+        "RI6":40,
+        "RI7":38775,
+        "RI8":true,
+        "RI9":"mo_Data",
+        "RI10": 3,
+        "RI11": 16,
+        "RI12": 0,
+        "RI13": 45,
+        "RI14": 3,
+        "RI15": [[1,2,3],[4,5,6]],
+        "NI1": false,
+        "NI2": 4,
+        "NI3": 3,
+        "NI4": "eps bearer",
+        "OI1": [0.5, 0.2, 0.333],
+        "VI1": "REGISTERED",
+        "VI2": 16,
+        "VI3": "[[10,20,30],[40,50,60]]",
+        "VI4": 5,
+        "HI1": 123456,
+        "HI2": 45678,
+        "NE1": "ne1 value",
+        "NE2": "ne2 value",
+        "NE3": "ne3 value",
+        "NE4": "ne4 value",
+        "NE5": "ne5 value",
+        "NE6": "ne6 value",
+        "NE7": "ne7 value",
+        "NE8": "ne8 value",
+        "NE9": "ne9 value",
+        "NE10": "ne10 value",
+        "NE11": "ne11 value",
+        "NE12": "ne12 value",
+        "NE13": "ne13 value",
+        "NE14": "ne14 value",
+        "NE15": "ne15 value",
+        "NE16": "ne16 value",
+        "NE17": "ne17 value",
+        "NE18": "ne18 value",
+        "NE19": "ne19 value",
+        "NE20": "ne20 value",
+        "NE21": "ne21 value",
+        "NE22": "ne22 value",
+        "NE23": "ne23 value",
+        "NE24": "ne24 value",
+        "NE25": "ne25 value",
+        "NE26": "ne26 value",
+
+        "NC1": 1,
+        "NC2": 2,
+        "NC3": 3,
+        "NC4": 5,
+        "NC5": 8,
+        "NC6": 13,
+        "NC7": 21,
+        "NC8": 34,
+        "NC9": 55
+        }}"#;
+
+#[test]
+fn sample_message() {
+    use serde_json;
+
+    let input = SAMPLE_MESSAGE;
+
     let message: ClientMessage = serde_json::from_str(input).unwrap();
     assert_eq!(message.seq_number, 1);
     assert_eq!(message.timestamp, 1502889770);
@@ -445,91 +669,7 @@ fn full_payload() {
     // let s = serde_json::to_string(&payload).unwrap();
     // assert_eq!(s, "heelo");
 
-    let input = r#"{ "seq_number": 1, "timestamp": 1522393388, "payload": {
-        "Name":"NE17",
-
-        "DI1": "abcdefg",
-        "DI2": "0127654",
-        "DI3": "34fe987",
-        "DI4": "kaios_phone_1",
-        "DI5": "2.5_r1",
-        "LI1": 234567,
-        "LI2": 567,
-        "LI3": 56789,
-        "LI4": 1212,
-        "LI5": 122.04,
-        "LI6": 37.3,
-        "LI7": true,
-        "LI8": 1.0,
-        "SI1": 57,
-        "SI2": 31,
-        "SI3": 67,
-        "TI1": 40,
-        "TI2": 43,
-
-        "RI1":37,
-        "RI2":8,
-        "RI3":5,
-        "RI4": 11,
-        "RI5": 0,
-        "RI6":40,
-        "RI7":38775,
-        "RI8":true,
-        "RI9":"mo_Data",
-        "RI10": 3,
-        "RI11": 16,
-        "RI12": 0,
-        "RI13": 45,
-        "RI14": 3,
-        "RI15": [[1,2,3],[4,5,6]],
-        "NI1": false,
-        "NI2": 4,
-        "NI3": 3,
-        "NI4": "eps bearer",
-        "OI1": [0.5, 0.2, 0.333],
-        "VI1": "REGISTERED",
-        "VI2": 16,
-        "VI3": "[[10,20,30],[40,50,60]]",
-        "VI4": 5,
-        "HI1": 123456,
-        "HI2": 45678,
-        "NE1": "ne1 value",
-        "NE2": "ne2 value",
-        "NE3": "ne3 value",
-        "NE4": "ne4 value",
-        "NE5": "ne5 value",
-        "NE6": "ne6 value",
-        "NE7": "ne7 value",
-        "NE8": "ne8 value",
-        "NE9": "ne9 value",
-        "NE10": "ne10 value",
-        "NE11": "ne11 value",
-        "NE12": "ne12 value",
-        "NE13": "ne13 value",
-        "NE14": "ne14 value",
-        "NE15": "ne15 value",
-        "NE16": "ne16 value",
-        "NE17": "ne17 value",
-        "NE18": "ne18 value",
-        "NE19": "ne19 value",
-        "NE20": "ne20 value",
-        "NE21": "ne21 value",
-        "NE22": "ne22 value",
-        "NE23": "ne23 value",
-        "NE24": "ne24 value",
-        "NE25": "ne25 value",
-        "NE26": "ne26 value",
-
-        "NC1": 1,
-        "NC2": 2,
-        "NC3": 3,
-        "NC4": 5,
-        "NC5": 8,
-        "NC6": 13,
-        "NC7": 21,
-        "NC8": 34,
-        "NC9": 55
-        }}"#;
+    let input = FULL_MESSAGE;
 
     let message: ClientMessage = serde_json::from_str(input).unwrap();
     assert_eq!(message.seq_number, 1);
@@ -537,6 +677,56 @@ fn full_payload() {
     assert_eq!(message.payload.name, "NE17");
 }
 
+#[test]
+fn filter_payload() {
+    use serde_json;
+
+    let payload: ClientPayload = serde_json::from_str(
+        r#"{"Name": "NE3", "DT": "now", "DI1": "imei", "LI2": 31, "RI1": 52,
+            "NC1": 1, "NC2": 2, "HI1": 100}"#,
+    ).unwrap();
+    let mut stats = FilterStats::default();
+
+    // The default filter keeps everything.
+    let filtered = payload
+        .clone()
+        .apply_filter(&FilterFrame::default(), &mut stats)
+        .unwrap();
+    assert_eq!(
+        serde_json::to_value(&filtered).unwrap(),
+        serde_json::to_value(&payload).unwrap()
+    );
+    assert_eq!(stats, FilterStats::default());
+
+    // Clear NC2 and the LI and HI categories.
+    let filter = FilterFrame {
+        nc: 0x7FFFFFFF & !0b10,
+        nd: 0x7FFFFFFF & !0b1_0000_0010,
+        ne: 0x7FFFFFFF,
+    };
+    let filtered = payload.clone().apply_filter(&filter, &mut stats).unwrap();
+    assert_eq!(
+        serde_json::to_string(&filtered).unwrap(),
+        r#"{"Name":"NE3","DT":"now","DI1":"imei","RI1":52,"NC1":1}"#
+    );
+    assert_eq!(stats.stripped_counters, 1);
+    assert_eq!(stats.stripped_data, 2);
+
+    // Clear NE3.
+    let filter = FilterFrame {
+        nc: 0x7FFFFFFF,
+        nd: 0x7FFFFFFF,
+        ne: 0x7FFFFFFF & !0b100,
+    };
+    assert!(payload.clone().apply_filter(&filter, &mut stats).is_none());
+    assert_eq!(stats.dropped_events, 1);
+
+    // Events that are not NE named are never dropped.
+    let mut other = payload;
+    other.name = "Counters".to_owned();
+    assert!(other.apply_filter(&filter, &mut stats).is_some());
+}
+
 #[test]
 fn decode_filter() {
     use serde_json;
@@ -548,4 +738,65 @@ fn decode_filter() {
 
     let message: FilterFrame = serde_json::from_str(input).unwrap();
     assert_eq!(message.nc, 3);
+
+    // With the NEn to bit n-1 and the ND category layouts, this filter keeps
+    // NE3 and NC1, but strips every data field category but DI and VI, which
+    // is why enforce_filter is off by default.
+    let payload: ClientMessage = serde_json::from_str(FULL_MESSAGE).unwrap();
+    let mut payload = payload.payload;
+    payload.name = "NE3".to_owned();
+    let mut stats = FilterStats::default();
+    let filtered = payload.apply_filter(&message, &mut stats).unwrap();
+    assert_eq!(filtered.DI1, Some("abcdefg".into()));
+    assert_eq!(filtered.VI1, Some("REGISTERED".into()));
+    assert!(filtered.LI1.is_none() && filtered.RI1.is_none());
+    assert!(stats.stripped_data > 0);
+}
+
+#[test]
+fn decode_records() {
+    use serde_json;
+
+    let record = json!({ "seq_number": 3, "payload": { "Name": "NE1" } });
+    assert_eq!(ClientMessage::seq_number_of(&record), Some(3));
+    match ClientMessage::from_record(record) {
+        Err(Error(ErrorKind::MissingTimestamp, _)) => {}
+        _ => panic!("Expected MissingTimestamp"),
+    }
+
+    for timestamp in &[json!(0), json!(-5), json!(1.5), json!("1502889770")] {
+        let record = json!({ "seq_number": 1, "timestamp": timestamp, "payload": {} });
+        match ClientMessage::from_record(record) {
+            Err(Error(ErrorKind::InvalidTimestamp, _)) => {}
+            _ => panic!("Expected InvalidTimestamp for {}", timestamp),
+        }
+    }
+
+    let record = json!({ "seq_number": 1, "timestamp": 1, "payload": { "Name": 12 } });
+    let err = ClientMessage::from_record(record).err().unwrap();
+    assert_eq!(err.description(), "InvalidJSON");
+
+    let record = json!({ "seq_number": 0 });
+    assert_eq!(ClientMessage::seq_number_of(&record), None);
+    let record = json!({ "seq_number": "1" });
+    assert_eq!(ClientMessage::seq_number_of(&record), None);
+
+    let record = serde_json::from_str(SAMPLE_MESSAGE).unwrap();
+    let message = ClientMessage::from_record(record).unwrap();
+    assert_eq!(message.payload.name, "NE1");
+}
+
+#[test]
+fn validate_payload() {
+    use serde_json;
+
+    let table = ValidationTable::strict();
+    let payload = ClientPayload::test_ri12().validate(&table).unwrap();
+    assert!(payload.DT.is_some());
+
+    let payload: ClientPayload =
+        serde_json::from_str(r#"{ "Name": "", "RI1": 120, "RI6": 1, "SI1": 50 }"#).unwrap();
+    let violations = payload.validate(&table).err().unwrap();
+    let codes: Vec<&str> = violations.iter().map(|v| v.code.as_str()).collect();
+    assert_eq!(codes, vec!["EmptyName", "InvalidRI1", "InvalidRI6"]);
 }
@@ -6,14 +6,22 @@
 // All other trademarks are the property of their respective owners.
 
 /// The messages exchanged among internal threads using the message broker.
+use admin::{AdminRequest, AdminResult};
+use config::Config;
 use frame_messages::{ClientPayload, FilterAck, FilterFrame};
 use socket_relay::SocketRelay;
+use std::sync::mpsc::Sender;
 
 #[derive(Clone, Debug)]
 pub enum InternalMessage {
-    NewClientMessage(ClientPayload),
+    NewClientMessage(String, ClientPayload), // The client source, and its payload.
     RelayReady(SocketRelay),
+    RelayAck(u64),
+    RelayLost(usize),
+    RelayUnsent(usize, Vec<Vec<u8>>), // The lines a lost relay connection didn't write.
     NewFilter(FilterFrame),
     FilterAck(FilterAck),
+    NewConfig(Box<Config>), // The reloaded configuration.
+    Admin(AdminRequest, Sender<AdminResult>), // A request from the admin socket, and where to reply.
     Shutdown,
 }
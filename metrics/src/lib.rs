@@ -0,0 +1,46 @@
+// (c) 2017 KAI OS TECHNOLOGIES (HONG KONG) LIMITED All rights reserved. This
+// file or any portion thereof may not be reproduced or used in any manner
+// whatsoever without the express written permission of KAI OS TECHNOLOGIES
+// (HONG KONG) LIMITED. KaiOS is the trademark of KAI OS TECHNOLOGIES (HONG KONG)
+// LIMITED or its affiliate company and may be registered in some jurisdictions.
+// All other trademarks are the property of their respective owners.
+
+//! The metrics daemon collects device metrics from local clients, and relays
+//! them to the data collection app.
+
+extern crate byteorder;
+extern crate chrono;
+#[macro_use]
+extern crate error_chain;
+extern crate flate2;
+extern crate hmac_sha256;
+extern crate libc;
+#[macro_use]
+extern crate log;
+extern crate mio;
+extern crate serde;
+extern crate serde_cbor;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate toml;
+
+pub mod admin;
+pub mod aggregation;
+pub mod cli;
+pub mod config;
+pub mod file_sink;
+pub mod frame;
+pub mod frame_messages;
+pub mod internal_messages;
+pub mod listener;
+pub mod message_broker;
+pub mod mqtt;
+pub mod privacy;
+pub mod queue;
+pub mod rollup;
+pub mod socket_relay;
+pub mod spool;
+pub mod stats;
+pub mod validation;
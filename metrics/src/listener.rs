@@ -5,354 +5,699 @@
 // LIMITED or its affiliate company and may be registered in some jurisdictions.
 // All other trademarks are the property of their respective owners.
 
+/// Client socket listener: a single mio event loop serves all the clients.
+use admin::{self, AdminRequest};
 use config::Config;
-use frame::{Frame, Result as FrameResult};
-use frame_messages::{ClientMessage, ErrorFrame, FilterAck, SharedFilterFrame, SuccessFrame};
+use frame::{Frame, FrameDecoder, FrameType, MAX_INFLATE_RATIO};
+use frame_messages::{
+    ClientMessage, ErrorFrame, FilterAck, ReadyFrame, SharedFilterFrame, SuccessFrame,
+};
 use internal_messages::InternalMessage;
 use libc;
 use message_broker::SharedMessageBroker;
-use std::collections::HashSet;
+use mio::unix::EventedFd;
+use mio::{Event, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use serde::Serialize;
+use serde_json::{self, Value};
+use std::collections::{HashMap, HashSet};
 use std::ffi::CString;
 use std::fs;
+use std::io::{self, ErrorKind as IoErrorKind, Read, Write};
 use std::net::Shutdown;
+use std::os::unix::io::AsRawFd;
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::{Path, PathBuf};
-// use std::sync::mpsc::channel;
-use std::sync::{Arc, Mutex};
+use std::path::Path;
+use std::result::Result as StdResult;
+use std::sync::mpsc::{channel, Receiver};
+use stats::STATS;
 use std::thread;
+use validation::Validator;
 
-type SharedSources = Arc<Mutex<HashSet<String>>>;
+const LISTENER: Token = Token(0);
+const MESSAGES: Token = Token(1);
+const FIRST_CLIENT: usize = 2;
 
-fn on_new_socket(
-    source_stream: UnixStream,
-    sources: SharedSources,
+// When this is an error, the connection is closed with the given reason.
+type ConnectionResult = StdResult<(), String>;
+
+/// The highest protocol version we speak. Clients that don't send a
+/// version are assumed to use version 1.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// The features supported by the daemon.
+const CAPABILITIES: [&str; 3] = ["filter_push", "compression", "batch_ack"];
+
+// Clients whose unread replies grow past this size are dropped.
+const MAX_PENDING_OUTPUT: usize = 1024 * 1024;
+
+// What a single read from a client got.
+enum Fill {
+    Data,
+    WouldBlock,
+    Closed,
+}
+
+// Reads the first Frame, which needs to be in the { "source": "ril_metrics" } format.
+#[derive(Deserialize, Debug)]
+struct InitFrame {
+    source: String,
+    #[serde(default = "default_protocol_version")]
+    protocol_version: u32,
+    #[serde(default)]
+    capabilities: Vec<String>,
+}
+
+fn default_protocol_version() -> u32 {
+    1
+}
+
+struct Connection {
+    stream: UnixStream,
+    decoder: FrameDecoder,
+    // Data waiting for the socket to be writable.
+    output: Vec<u8>,
+    writable: bool,
+    // Set once the handshake is done.
+    source: Option<String>,
+    // Replies use the encoding of the init frame.
+    encoding: FrameType,
+    // True to acknowledge a whole batch with a single success frame.
+    batch_ack: bool,
+    last_seq_number: u64,
+}
+
+impl Connection {
+    fn new(stream: UnixStream, max_frame_size: usize) -> Self {
+        Connection {
+            stream,
+            decoder: FrameDecoder::with_max_frame_size(max_frame_size),
+            output: vec![],
+            writable: false,
+            source: None,
+            encoding: FrameType::Json,
+            batch_ack: false,
+            last_seq_number: 0,
+        }
+    }
+
+    fn send(&mut self, frame: Frame) -> ConnectionResult {
+        frame
+            .write_to(&mut self.output)
+            .map_err(|err| format!("{:?}", err))?;
+        self.flush()?;
+        if self.output.len() > MAX_PENDING_OUTPUT {
+            return Err(format!("{} bytes of replies are not read", self.output.len()));
+        }
+        Ok(())
+    }
+
+    fn send_obj<T: Serialize>(&mut self, obj: &T) -> ConnectionResult {
+        let frame = Frame::encode(obj, self.encoding);
+        self.send(frame)
+    }
+
+    // Writes as much as possible of the pending output without blocking.
+    fn flush(&mut self) -> ConnectionResult {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err("Connection closed while writing".into()),
+                Ok(count) => {
+                    self.output.drain(..count);
+                }
+                Err(ref err) if err.kind() == IoErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == IoErrorKind::Interrupted => {}
+                Err(err) => return Err(format!("{}", err)),
+            }
+        }
+        Ok(())
+    }
+
+    // Feeds the decoder with a single read, so that frames are checked before
+    // we buffer more data.
+    fn fill(&mut self) -> StdResult<Fill, String> {
+        let mut buffer = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(Fill::Closed),
+                Ok(count) => {
+                    self.decoder.feed(&buffer[..count]);
+                    return Ok(Fill::Data);
+                }
+                Err(ref err) if err.kind() == IoErrorKind::WouldBlock => return Ok(Fill::WouldBlock),
+                Err(ref err) if err.kind() == IoErrorKind::Interrupted => {}
+                Err(err) => return Err(format!("{}", err)),
+            }
+        }
+    }
+
+    fn interest(&self) -> Ready {
+        if self.output.is_empty() {
+            Ready::readable()
+        } else {
+            Ready::readable() | Ready::writable()
+        }
+    }
+}
+
+pub struct Listener {
+    socket: UnixListener,
+    path: String,
     broker: SharedMessageBroker<InternalMessage>,
     filter: SharedFilterFrame,
-) {
-    let mut source = String::new();
+    connections: HashMap<Token, Connection>,
+    max_frame_size: usize,
+    validator: Validator,
+    next_token: usize,
+    sources: HashSet<String>,
+    messages: Receiver<InternalMessage>,
+    // Keeps the messages registration alive, and lets us reset its readiness.
+    _registration: Registration,
+    readiness: SetReadiness,
+    done: bool,
+}
 
-    let shared_stream = Arc::new(Mutex::new(source_stream));
+// Binds the client socket, replacing any stale socket file.
+fn bind(spath: &str) -> io::Result<UnixListener> {
+    if Path::exists(Path::new(spath)) {
+        #[allow(unused_must_use)]
+        {
+            fs::remove_file(spath);
+        }
+    }
 
-    macro_rules! stry {
-        ($e:expr) => {
-            match $e {
-                Err(e) => {
-                    {
-                        let mut guard = sources.lock().unwrap();
-                        guard.remove(&source);
+    let socket = UnixListener::bind(spath)?;
+    socket.set_nonblocking(true)?;
+
+    // chmod the socket to 660
+    let cpath = CString::new(spath).unwrap();
+    if unsafe { libc::chmod(cpath.as_ptr(), 0o660) } != 0 {
+        // Unfortunately the libc crate doesn't expose errno :(
+        error!("Failed to chmod 0660 {}", spath);
+    } else {
+        info!("Successfully chmod 0660 {}", spath);
+    }
+    Ok(socket)
+}
+
+impl Listener {
+    /// Binds the client socket and registers it, as well as the messages
+    /// we get from the broker, with `poll`.
+    pub fn new(
+        config: &Config,
+        broker: SharedMessageBroker<InternalMessage>,
+        filter: SharedFilterFrame,
+        poll: &Poll,
+    ) -> io::Result<Self> {
+        debug!(
+            "Starting the metrics socket endpoint at {:?}",
+            config.socket_path
+        );
+        let spath = config.socket_path.clone();
+        let socket = bind(&spath)?;
+
+        poll.register(
+            &EventedFd(&socket.as_raw_fd()),
+            LISTENER,
+            Ready::readable(),
+            PollOpt::level(),
+        )?;
+
+        // Broker messages are relayed by a helper thread that wakes up the poll.
+        let (registration, readiness) = Registration::new2();
+        poll.register(&registration, MESSAGES, Ready::readable(), PollOpt::edge())?;
+        let (tx, rx) = channel::<InternalMessage>();
+        let (relay_tx, messages) = channel::<InternalMessage>();
+        let relay_readiness = readiness.clone();
+        thread::Builder::new()
+            .name("listener messages".to_owned())
+            .spawn(move || {
+                for msg in rx {
+                    if relay_tx.send(msg).is_err() {
+                        return;
                     }
-                    debug!("Closing connection: {:?} {}", e, source);
-                    let stream = shared_stream.lock().unwrap();
-                    stream
-                        .shutdown(Shutdown::Both)
-                        .expect("shutdown function failed");
-                    return;
+                    let _ = relay_readiness.set_readiness(Ready::readable());
                 }
-                Ok(val) => val,
+            })?;
+        broker
+            .lock()
+            .unwrap()
+            .add_actor("listener", tx)
+            .map_err(|err| io::Error::new(io::ErrorKind::AlreadyExists, format!("{:?}", err)))?;
+
+        Ok(Listener {
+            socket,
+            path: spath,
+            broker,
+            filter,
+            connections: HashMap::new(),
+            max_frame_size: config.max_frame_size,
+            validator: Validator::new(config),
+            next_token: FIRST_CLIENT,
+            sources: HashSet::new(),
+            messages,
+            _registration: registration,
+            readiness,
+            done: false,
+        })
+    }
+
+    // Moves the client socket to a new path. Connected clients are kept.
+    fn rebind(&mut self, poll: &Poll, path: &str) {
+        let socket = match bind(path) {
+            Ok(socket) => socket,
+            Err(err) => {
+                error!("Failed to bind {}, keeping {}: {}", path, self.path, err);
+                return;
             }
         };
+        let registered = poll.register(
+            &EventedFd(&socket.as_raw_fd()),
+            LISTENER,
+            Ready::readable(),
+            PollOpt::level(),
+        );
+        if let Err(err) = registered {
+            error!("Failed to register {}, keeping {}: {}", path, self.path, err);
+            let _ = fs::remove_file(path);
+            return;
+        }
+        let _ = poll.deregister(&EventedFd(&self.socket.as_raw_fd()));
+        info!("Client socket moved from {} to {}", self.path, path);
+        let _ = fs::remove_file(&self.path);
+        self.socket = socket;
+        self.path = path.to_owned();
+    }
+
+    /// Returns true once we got the Shutdown message.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Stops accepting clients and removes the socket file, then processes
+    /// the frames the clients already sent before closing their connections.
+    pub fn shutdown(&mut self, poll: &Poll) {
+        if self.done {
+            return;
+        }
+        info!("Shutting down listener");
+        let _ = poll.deregister(&EventedFd(&self.socket.as_raw_fd()));
+        if let Err(err) = fs::remove_file(&self.path) {
+            error!("Failed to remove {}: {}", self.path, err);
+        }
+
+        let tokens: Vec<Token> = self.connections.keys().cloned().collect();
+        for token in tokens {
+            let mut connection = self.connections.remove(&token).unwrap();
+            let res = connection
+                .fill()
+                .and_then(|_| self.process_frames(&mut connection))
+                .and_then(|_| connection.flush());
+            if let Err(reason) = res {
+                debug!("Failed to drain connection: {}", reason);
+            }
+            self.close(poll, connection, "Shutting down");
+        }
+
+        let _ = self.broker.lock().unwrap().remove_actor("listener");
+        self.done = true;
     }
 
-    macro_rules! read_frame {
-        ($t:ty) => {{
-            let mut stream = shared_stream.lock().unwrap();
-            match Frame::read_from(&mut *stream) {
+    /// Handles an event of the poll we registered with.
+    pub fn ready(&mut self, poll: &Poll, event: &Event) {
+        match event.token() {
+            LISTENER => self.accept(poll),
+            MESSAGES => self.on_messages(poll),
+            token => self.on_connection_event(poll, token, event.readiness()),
+        }
+    }
+
+    fn accept(&mut self, poll: &Poll) {
+        loop {
+            let stream = match self.socket.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref err) if err.kind() == IoErrorKind::WouldBlock => return,
                 Err(err) => {
-                    {
-                        let mut guard = sources.lock().unwrap();
-                        guard.remove(&source);
-                    }
-                    debug!(
-                        "Invalid frame ({:?}), closing connection for {}",
-                        err, source
-                    );
-                    stream
-                        .shutdown(Shutdown::Both)
-                        .expect("shutdown function failed");
+                    error!("Failed to accept connection: {}", err);
                     return;
                 }
-                Ok(val) => {
-                    let obj: FrameResult<$t> = val.deserialize();
-                    stry!(obj)
-                }
+            };
+
+            debug!("New socket");
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            let registered = stream.set_nonblocking(true).and_then(|_| {
+                poll.register(
+                    &EventedFd(&stream.as_raw_fd()),
+                    token,
+                    Ready::readable(),
+                    PollOpt::level(),
+                )
+            });
+            if let Err(err) = registered {
+                error!("Failed to register connection: {}", err);
+                continue;
             }
-        }};
+            self.connections
+                .insert(token, Connection::new(stream, self.max_frame_size));
+        }
     }
 
-    macro_rules! try_read_frame {
-        ($t:ty) => {{
-            let mut stream = shared_stream.lock().unwrap();
-            match Frame::read_from(&mut *stream) {
-                Err(err) => {
-                    {
-                        let mut guard = sources.lock().unwrap();
-                        guard.remove(&source);
+    fn on_messages(&mut self, poll: &Poll) {
+        let _ = self.readiness.set_readiness(Ready::empty());
+        while let Ok(msg) = self.messages.try_recv() {
+            match msg {
+                InternalMessage::NewFilter(filter) => {
+                    info!("About to send filter to clients: {:?}", filter);
+                    let tokens: Vec<Token> = self.connections.keys().cloned().collect();
+                    for token in tokens {
+                        let res = {
+                            let connection = self.connections.get_mut(&token).unwrap();
+                            if connection.source.is_none() {
+                                continue;
+                            }
+                            connection.send_obj(&filter)
+                        };
+                        self.after_io(poll, token, res);
                     }
-                    debug!(
-                        "Invalid frame ({:?}), closing connection for {}",
-                        err, source
-                    );
-                    stream
-                        .shutdown(Shutdown::Both)
-                        .expect("shutdown function failed");
-                    return;
                 }
-                Ok(val) => {
-                    let obj: FrameResult<$t> = val.deserialize();
-                    (val, obj)
+                InternalMessage::Admin(request, reply) => {
+                    let result = self.on_admin_request(poll, request);
+                    admin::reply(&reply, result);
+                }
+                InternalMessage::NewConfig(ref config) if config.socket_path != self.path => {
+                    self.rebind(poll, &config.socket_path);
+                }
+                InternalMessage::Shutdown => self.shutdown(poll),
+                _ => {
+                    // Nothing to do with the other messages.
                 }
             }
-        }};
+        }
     }
 
-    debug!("New socket");
+    fn on_admin_request(&mut self, poll: &Poll, request: AdminRequest) -> admin::AdminResult {
+        match request {
+            AdminRequest::ListSources => {
+                let mut sources: Vec<&String> = self.sources.iter().collect();
+                sources.sort();
+                Ok(json!(sources))
+            }
+            AdminRequest::DropSource(source) => {
+                let token = self
+                    .connections
+                    .iter()
+                    .find(|&(_, connection)| connection.source.as_ref() == Some(&source))
+                    .map(|(token, _)| *token);
+                if let Some(token) = token {
+                    info!("Dropping source {} from the admin socket", source);
+                    self.after_io(poll, token, Err("Dropped by admin".into()));
+                }
+                Ok(json!({ "dropped": token.is_some() }))
+            }
+            _ => Err(format!("Unexpected request: {:?}", request)),
+        }
+    }
+
+    fn on_connection_event(&mut self, poll: &Poll, token: Token, readiness: Ready) {
+        let mut connection = match self.connections.remove(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
 
-    // Reads the first Frame, which needs to be in the { "source": "ril_metrics" } format.
-    #[derive(Deserialize, Debug)]
-    struct InitFrame {
-        source: String,
+        let mut res = Ok(());
+        if readiness.is_writable() {
+            res = connection.flush();
+        }
+        if res.is_ok() && readiness.is_readable() {
+            res = self.read_frames(&mut connection);
+        }
+
+        self.connections.insert(token, connection);
+        self.after_io(poll, token, res);
     }
-    let init_frame = read_frame!(InitFrame);
 
-    source = init_frame.source;
+    // Waits for the connection to be writable while some output is pending.
+    fn update_interest(poll: &Poll, token: Token, connection: &mut Connection) {
+        let interest = connection.interest();
+        if interest.is_writable() != connection.writable {
+            connection.writable = interest.is_writable();
+            let _ = poll.reregister(
+                &EventedFd(&connection.stream.as_raw_fd()),
+                token,
+                interest,
+                PollOpt::level(),
+            );
+        }
+    }
 
-    // Verify is this source is not already active.
-    {
-        let mut guard = sources.lock().unwrap();
-        if guard.contains(&source) {
-            debug!("Source already connected, closing connection");
-            let stream = shared_stream.lock().unwrap();
-            stream
-                .shutdown(Shutdown::Both)
-                .expect("shutdown function failed");
-            return;
-        } else {
-            guard.insert(source.clone());
+    // Closes connections that failed when we wrote to them outside of their
+    // own events, and flushes the output of the others once they are writable.
+    fn after_io(&mut self, poll: &Poll, token: Token, res: ConnectionResult) {
+        let mut connection = match self.connections.remove(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        match res {
+            Ok(()) => {
+                Listener::update_interest(poll, token, &mut connection);
+                self.connections.insert(token, connection);
+            }
+            Err(reason) => self.close(poll, connection, &reason),
         }
     }
 
-    info!("Accepting connection from {}", source);
-
-    {
-        let ack = Frame::from_json(&json!({ "ready": true }));
-        let mut stream = shared_stream.lock().unwrap();
-        stry!(ack.write_to(&mut *stream));
-    }
-
-    // Send the current filter.
-    // {
-    //     let filter = &(*filter.lock().unwrap()).get();
-    //     let frame = Frame::from_obj(filter);
-    //     let mut stream = shared_stream.lock().unwrap();
-    //     stry!(frame.write_to(&mut *stream));
-    //     debug!("Sent initial filter: {:?}", filter);
-    // }
-
-    // Register as a broker receive notices of changes in the filtering and
-    // push it to the client.
-    // let (tx, rx) = channel::<InternalMessage>();
-    // {
-    //     let mut guard = broker.lock().unwrap();
-    //     guard
-    //         .add_actor(
-    //             &format!("client-{}-{}", source, sources.lock().unwrap().len()),
-    //             tx.clone(),
-    //         )
-    //         .unwrap();
-    // }
-
-    // let shared_stream2 = shared_stream.clone();
-    // let sources2 = sources.clone();
-    // let source2 = source.clone();
-    // thread::Builder::new()
-    //     .name("listener filter".to_owned())
-    //     .spawn(move || {
-    //         macro_rules! stry2 {
-    //             ($e:expr) => {
-    //                 match $e {
-    //                     Err(e) => {
-    //                         {
-    //                             let mut guard = sources2.lock().unwrap();
-    //                             guard.remove(&source2);
-    //                         }
-    //                         debug!("Closing connection: {:?} {}", e, source2);
-    //                         let stream = shared_stream2.lock().unwrap();
-    //                         stream
-    //                             .shutdown(Shutdown::Both)
-    //                             .expect("shutdown function failed");
-    //                         return;
-    //                     }
-    //                     Ok(val) => val,
-    //                 }
-    //             };
-    //         }
-
-    //         loop {
-    //             let msg = rx.recv().unwrap();
-    //             match msg {
-    //                 InternalMessage::NewFilter(filter) => {
-    //                     // send the message to the stream.
-    //                     info!("About to send filter to RIL: {:?}", filter);
-    //                     let frame = Frame::from_obj(&filter);
-    //                     let mut stream = shared_stream2.lock().unwrap();
-    //                     info!("Stream lock acquired");
-    //                     stry2!(frame.write_to(&mut *stream));
-    //                     info!("Updated filter: {:?}", filter);
-    //                 }
-    //                 InternalMessage::Shutdown => {
-    //                     info!("Shutting down queue manager thread");
-    //                     break;
-    //                 }
-    //                 _ => {
-    //                     // Nothing to do with the other messages.
-    //                 }
-    //             }
-    //         }
-    //     })
-    //     .expect("Failed to start filter receiving thread");
-
-    // Once we have sent the readiness packet, loop while we get more
-    // valid frames from the client, and dispatch them.
-    let mut last_seq_number = 0u64;
-    loop {
-        // Check if this is a filter ack.
-        let (val, filter) = try_read_frame!(FilterAck);
-        if let Ok(filter_ack) = filter {
-            // Relay to the socket and bail out of this loop iteration.
-            debug!("FilterAck is {:?}", filter_ack);
+    fn close(&mut self, poll: &Poll, connection: Connection, reason: &str) {
+        let _ = poll.deregister(&EventedFd(&connection.stream.as_raw_fd()));
+        let _ = connection.stream.shutdown(Shutdown::Both);
+        self.forget(connection, reason);
+    }
 
-            // Push the frame to the queue.
-            stry!(
-                broker
-                    .lock()
-                    .unwrap()
-                    .send_message("queue", InternalMessage::FilterAck(filter_ack))
-            );
+    fn forget(&mut self, connection: Connection, reason: &str) {
+        let source = connection.source.unwrap_or_default();
+        debug!("Closing connection: {} {}", reason, source);
+        self.sources.remove(&source);
+    }
 
-            continue;
-        } else {
-            debug!("Not a filter ack");
+    // Processes the frames of each read, until there is nothing left to read.
+    fn read_frames(&mut self, connection: &mut Connection) -> ConnectionResult {
+        loop {
+            match connection.fill()? {
+                Fill::Data => self.process_frames(connection)?,
+                Fill::WouldBlock => return Ok(()),
+                Fill::Closed => return Err("Connection closed by peer".into()),
+            }
         }
+    }
 
-        let decoded: FrameResult<Vec<ClientMessage>> = val.deserialize();
-        let messages = stry!(decoded);
+    fn process_frames(&mut self, connection: &mut Connection) -> ConnectionResult {
+        loop {
+            let frame = match connection.decoder.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(()),
+                Err(err) => return Err(format!("Invalid frame ({:?})", err)),
+            };
+            if connection.source.is_none() {
+                self.on_init_frame(connection, &frame)?;
+            } else {
+                self.on_client_frame(connection, &frame)?;
+            }
+        }
+    }
+
+    fn on_init_frame(&mut self, connection: &mut Connection, frame: &Frame) -> ConnectionResult {
+        let init_frame: InitFrame = frame
+            .deserialize_with_limit(self.max_inflated_size())
+            .map_err(|err| format!("{:?}", err))?;
+        let source = init_frame.source;
+
+        // Verify is this source is not already active.
+        if self.sources.contains(&source) {
+            return Err(format!("Source {} already connected", source));
+        }
+        self.sources.insert(source.clone());
+        connection.source = Some(source.clone());
+        connection.encoding = frame.typ;
+
+        // Batch acks are only used by clients asking for them.
+        let protocol_version = init_frame.protocol_version.min(PROTOCOL_VERSION);
+        connection.batch_ack = protocol_version >= 2
+            && init_frame.capabilities.iter().any(|cap| cap == "batch_ack");
+
+        info!(
+            "Accepting connection from {} (protocol version {})",
+            source, protocol_version
+        );
+        let ready = ReadyFrame {
+            ready: true,
+            protocol_version,
+            capabilities: CAPABILITIES.iter().map(|cap| cap.to_string()).collect(),
+            compression: vec!["deflate".into()],
+        };
+        connection.send_obj(&ready)?;
 
-        for message in messages {
+        // Send the current filter.
+        let filter = self.filter.lock().unwrap().get();
+        connection.send_obj(&filter)?;
+        debug!("Sent initial filter: {:?}", filter);
+        Ok(())
+    }
+
+    // Deflate payloads may inflate to a few times the maximum frame size.
+    fn max_inflated_size(&self) -> usize {
+        self.max_frame_size.saturating_mul(MAX_INFLATE_RATIO)
+    }
+
+    fn send_to_queue(&self, msg: InternalMessage) -> ConnectionResult {
+        self.broker
+            .lock()
+            .unwrap()
+            .send_message("queue", msg)
+            .map_err(|err| format!("{:?}", err))
+    }
+
+    fn on_client_frame(&mut self, connection: &mut Connection, frame: &Frame) -> ConnectionResult {
+        let source = connection.source.clone().unwrap_or_default();
+        STATS.frame_received(&source);
+
+        // The payload is decoded once: a batch of records is an array, and
+        // anything else has to be a filter ack.
+        let payload: Value = frame
+            .deserialize_with_limit(self.max_inflated_size())
+            .map_err(|err| format!("{:?}", err))?;
+        let records = match payload {
+            Value::Array(records) => records,
+            payload => {
+                let filter_ack: FilterAck =
+                    serde_json::from_value(payload).map_err(|err| format!("{:?}", err))?;
+                // Relay to the socket and bail out.
+                debug!("FilterAck is {:?}", filter_ack);
+                return self.send_to_queue(InternalMessage::FilterAck(filter_ack));
+            }
+        };
+
+        // Records are decoded one by one, so that a malformed record
+        // doesn't prevent the others from being accepted.
+
+        let mut last_success = None;
+        for record in records {
+            let seq_number = match ClientMessage::seq_number_of(&record) {
+                Some(seq_number) => seq_number,
+                // We can't correlate errors with this record.
+                None => return Err("Missing or invalid seq_number".into()),
+            };
+            if seq_number <= connection.last_seq_number && seq_number != 1 {
+                // Close the connection.
+                return Err("Invalid seq_number".into());
+            } else {
+                connection.last_seq_number = seq_number;
+            }
+
+            let message = match ClientMessage::from_record(record) {
+                Ok(message) => message,
+                Err(err) => {
+                    debug!("Invalid record from {}: {}", source, err);
+                    STATS.validation_failed(err.description());
+                    let msg = ErrorFrame {
+                        success: false,
+                        seq_number,
+                        error: err.description().to_string(),
+                        profile: None,
+                        violations: vec![],
+                    };
+                    connection.send_obj(&msg)?;
+                    continue;
+                }
+            };
             debug!(
                 "Got frame from {}: seq={}, timestamp={}",
                 source, message.seq_number, message.timestamp
             );
-            if message.seq_number <= last_seq_number && message.seq_number != 1 {
-                // Close the connection.
-                debug!("Invalid seq_number, closing connection");
-                let stream = shared_stream.lock().unwrap();
-                stream
-                    .shutdown(Shutdown::Both)
-                    .expect("shutdown function failed");
-                return;
-            } else {
-                last_seq_number = message.seq_number;
-            }
 
             // Validate the payload.
-            let payload = message.payload.validate();
-            if let Err(err) = payload {
-                debug!("Invalid payload: {}", err);
+            if let Some((profile, violations)) = self.validator.validate(&message.payload) {
+                debug!("Invalid payload ({:?}): {:?}", profile, violations);
+                STATS.validation_failed(&violations[0].code);
 
                 // Send an error payload.
                 let msg = ErrorFrame {
                     success: false,
                     seq_number: message.seq_number,
-                    error: format!("{}", err.description()),
+                    error: violations[0].code.clone(),
+                    profile: profile.map(|name| name.to_owned()),
+                    violations,
                 };
-                let frame = Frame::from_obj(&msg);
-
-                let mut stream = shared_stream.lock().unwrap();
-                stry!(frame.write_to(&mut *stream));
+                connection.send_obj(&msg)?;
                 continue;
             }
+            let mut payload = message.payload;
+            payload.set_default_date();
 
             // Push the frame to the queue.
-            stry!(
-                broker
-                    .lock()
-                    .unwrap()
-                    .send_message("queue", InternalMessage::NewClientMessage(payload.unwrap()))
-            );
+            self.send_to_queue(InternalMessage::NewClientMessage(source.clone(), payload))?;
+            last_success = Some(message.seq_number);
+            if connection.batch_ack {
+                continue;
+            }
 
             // Return a success message.
             let msg = SuccessFrame {
                 success: true,
                 seq_number: message.seq_number,
             };
-            let frame = Frame::from_obj(&msg);
+            connection.send_obj(&msg)?;
+        }
 
-            {
-                let mut stream = shared_stream.lock().unwrap();
-                stry!(frame.write_to(&mut *stream));
-            }
+        // Acknowledge the batch up to the last successful message. Errors
+        // were already reported individually.
+        if let (true, Some(seq_number)) = (connection.batch_ack, last_success) {
+            let msg = SuccessFrame {
+                success: true,
+                seq_number,
+            };
+            connection.send_obj(&msg)?;
         }
+        Ok(())
     }
 }
 
-pub fn start_listener(
+/// Runs the listener event loop on its own thread, until it gets the Shutdown
+/// message. The daemon runs it on its main thread instead.
+#[cfg(test)]
+pub(crate) fn start_listener(
     config: &Config,
     broker: SharedMessageBroker<InternalMessage>,
     filter: SharedFilterFrame,
-) {
-    debug!(
-        "Starting the metrics socket endpoint at {:?}",
-        config.socket_path
-    );
-    let config = config.clone();
+) -> thread::JoinHandle<()> {
+    use mio::Events;
 
-    let sources = Arc::new(Mutex::new(HashSet::<String>::new()));
+    let config = config.clone();
 
     thread::Builder::new()
         .name("socket listener".to_owned())
         .spawn(move || {
-            let spath = config.socket_path.clone();
-            if Path::exists(Path::new(&config.socket_path)) {
-                #[allow(unused_must_use)]
-                {
-                    fs::remove_file(spath.clone());
-                }
-            }
-
-            let path = PathBuf::from(spath.clone());
-
-            let socket = match UnixListener::bind(path) {
-                Ok(sock) => sock,
+            let poll = Poll::new().expect("Failed to create poll");
+            let mut listener = match Listener::new(&config, broker, filter, &poll) {
+                Ok(listener) => listener,
                 Err(e) => {
                     error!("Couldn't bind: {:?}", e);
                     return;
                 }
             };
 
-            // chmod the socket to 660
-            let cpath = CString::new(spath.clone()).unwrap();
-            if unsafe { libc::chmod(cpath.as_ptr(), 0o660) } != 0 {
-                // Unfortunately the libc crate doesn't expose errno :(
-                error!("Failed to chmod 0660 {}", spath);
-            } else {
-                info!("Successfully chmod 0660 {}", spath);
-            }
-
-            for stream in socket.incoming() {
-                match stream {
-                    Ok(stream) => {
-                        let s = sources.clone();
-                        let b = broker.clone();
-                        let f = filter.clone();
-                        thread::spawn(move || on_new_socket(stream, s, b, f));
-                    }
-                    Err(_) => {
-                        break;
+            let mut events = Events::with_capacity(1024);
+            while !listener.is_done() {
+                if let Err(err) = poll.poll(&mut events, None) {
+                    if err.kind() != IoErrorKind::Interrupted {
+                        error!("Listener poll failed: {}", err);
+                        return;
                     }
                 }
+                for event in events.iter() {
+                    listener.ready(&poll, &event);
+                }
             }
         })
-        .expect("Failed to start socket listener thread");
+        .expect("Failed to start socket listener thread")
 }
 
 #[test]
@@ -360,7 +705,7 @@ fn test_listener() {
     use chrono::{Timelike, Utc};
     use frame_messages::{default_shared_filterframe, ClientPayload, FilterFrame};
     use message_broker::MessageBroker;
-    use std::io::Read;
+    use std::io::{Read, Write};
     use std::net::TcpListener;
     use std::sync::mpsc::channel;
     use std::time::Duration;
@@ -393,11 +738,11 @@ fn test_listener() {
                 .unwrap();
             assert_eq!(ack.ready, true);
 
-            // let filter: FilterFrame = Frame::read_from(&mut stream)
-            //     .unwrap()
-            //     .deserialize()
-            //     .unwrap();
-            // assert_eq!(filter, FilterFrame::default());
+            let filter: FilterFrame = Frame::read_from(&mut stream)
+                .unwrap()
+                .deserialize()
+                .unwrap();
+            assert_eq!(filter, FilterFrame::default());
 
             // Send the filter ack.
             let ack = Frame::from_json(&json!({ "success": true, "kind": "FilterAck" }));
@@ -466,6 +811,8 @@ fn test_listener() {
             assert_eq!(res.success, false);
             assert_eq!(res.seq_number, 4);
             assert_eq!(res.error, "InvalidRI6".to_owned());
+            assert_eq!(res.violations.len(), 1);
+            assert_eq!(res.violations[0].field, "RI6");
 
             // Read the JSON on the relay socket.
             // These are JSON strings with a '\n ' delimiter.
@@ -506,6 +853,33 @@ fn test_listener() {
             assert_eq!(res.success, true);
             assert_eq!(res.seq_number, 5);
 
+            // A new filter sent by the relay consumer is pushed to the client.
+            relay
+                .write_all(b"{\"NC\": 1, \"ND\": 2, \"NE\": 3}\n")
+                .unwrap();
+            let filter: FilterFrame = Frame::read_from(&mut stream)
+                .unwrap()
+                .deserialize()
+                .unwrap();
+            assert_eq!(filter.nc, 1);
+            assert_eq!(filter.nd, 2);
+            assert_eq!(filter.ne, 3);
+
+            // New clients get the updated filter right after the handshake.
+            let mut stream2 = UnixStream::connect("/tmp/metrics_daemon_1").unwrap();
+            let init = Frame::from_json(&json!({ "source": "test_source_2" }));
+            init.write_to(&mut stream2).unwrap();
+            let ack: AckFrame = Frame::read_from(&mut stream2)
+                .unwrap()
+                .deserialize()
+                .unwrap();
+            assert_eq!(ack.ready, true);
+            let filter2: FilterFrame = Frame::read_from(&mut stream2)
+                .unwrap()
+                .deserialize()
+                .unwrap();
+            assert_eq!(filter2, filter);
+
             // Signals that this thread is done.
             tx.send(()).unwrap();
         })
@@ -517,6 +891,7 @@ fn test_listener() {
         buffer_size: 10,
         relay_port: 12345,
         verbose: false,
+        ..Config::default()
     };
 
     let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
@@ -615,6 +990,7 @@ fn test_sources() {
         buffer_size: 10,
         relay_port: 54321,
         verbose: false,
+        ..Config::default()
     };
 
     let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
@@ -632,3 +1008,498 @@ fn test_sources() {
         .unwrap()
         .broadcast_message(InternalMessage::Shutdown);
 }
+
+#[test]
+fn test_partial_frames() {
+    use frame_messages::default_shared_filterframe;
+    use message_broker::MessageBroker;
+    use std::time::Duration;
+
+    let config = Config {
+        socket_path: "/tmp/metrics_daemon_3".to_owned(),
+        relay_port: 54322,
+        ..Config::default()
+    };
+
+    let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
+    let filter = default_shared_filterframe();
+    ::queue::start_queue_manager(&config, broker.clone(), filter.clone());
+    start_listener(&config, broker.clone(), filter);
+    thread::sleep(Duration::from_millis(200));
+
+    // Split the init frame and the first batch in several writes.
+    let mut data = vec![];
+    Frame::from_json(&json!({ "source": "test_source" }))
+        .write_to(&mut data)
+        .unwrap();
+    Frame::from_json(&json!([{ "seq_number": 1, "timestamp": 1, "payload": { "Name": "NE1" } }]))
+        .write_to(&mut data)
+        .unwrap();
+
+    let mut stream = UnixStream::connect("/tmp/metrics_daemon_3").unwrap();
+    for chunk in data.chunks(7) {
+        stream.write_all(chunk).unwrap();
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let ack = Frame::read_from(&mut stream).unwrap().json().unwrap();
+    assert_eq!(ack["ready"], json!(true));
+    assert_eq!(ack["protocol_version"], json!(1));
+    let _filter = Frame::read_from(&mut stream).unwrap();
+    let res: SuccessFrame = Frame::read_from(&mut stream)
+        .unwrap()
+        .deserialize()
+        .unwrap();
+    assert!(res.success);
+    assert_eq!(res.seq_number, 1);
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+}
+
+#[test]
+fn test_cbor_client() {
+    use frame_messages::{default_shared_filterframe, FilterFrame};
+    use message_broker::MessageBroker;
+    use serde_json;
+    use std::time::Duration;
+
+    let config = Config {
+        socket_path: "/tmp/metrics_daemon_4".to_owned(),
+        relay_port: 54323,
+        ..Config::default()
+    };
+
+    let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
+    let filter = default_shared_filterframe();
+    ::queue::start_queue_manager(&config, broker.clone(), filter.clone());
+    start_listener(&config, broker.clone(), filter);
+    thread::sleep(Duration::from_millis(200));
+
+    let mut stream = UnixStream::connect("/tmp/metrics_daemon_4").unwrap();
+    Frame::encode(&json!({ "source": "test_source" }), FrameType::Cbor)
+        .write_to(&mut stream)
+        .unwrap();
+
+    // All the replies use the client encoding.
+    let ack = Frame::read_from(&mut stream).unwrap();
+    assert_eq!(ack.typ, FrameType::Cbor);
+    assert_eq!(ack.json().unwrap()["ready"], json!(true));
+    let filter = Frame::read_from(&mut stream).unwrap();
+    assert_eq!(filter.typ, FrameType::Cbor);
+    assert_eq!(
+        filter.deserialize::<FilterFrame>().unwrap(),
+        FilterFrame::default()
+    );
+
+    let msg: ClientMessage = serde_json::from_value(
+        json!({ "seq_number": 1, "timestamp": 1, "payload": { "Name": "NE1" } }),
+    )
+    .unwrap();
+    let empty = ClientMessage {
+        seq_number: 2,
+        timestamp: 2,
+        ..ClientMessage::default()
+    };
+    Frame::encode(&vec![msg, empty], FrameType::Cbor)
+        .write_to(&mut stream)
+        .unwrap();
+
+    let res: SuccessFrame = Frame::read_from(&mut stream)
+        .unwrap()
+        .deserialize()
+        .unwrap();
+    assert!(res.success);
+    assert_eq!(res.seq_number, 1);
+    let res: ErrorFrame = Frame::read_from(&mut stream)
+        .unwrap()
+        .deserialize()
+        .unwrap();
+    assert!(!res.success);
+    assert_eq!(res.seq_number, 2);
+    assert_eq!(res.error, "EmptyName");
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+}
+
+#[test]
+fn test_deflate_client() {
+    use frame_messages::default_shared_filterframe;
+    use message_broker::MessageBroker;
+    use std::time::Duration;
+
+    let config = Config {
+        socket_path: "/tmp/metrics_daemon_5".to_owned(),
+        relay_port: 54324,
+        ..Config::default()
+    };
+
+    let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
+    let filter = default_shared_filterframe();
+    ::queue::start_queue_manager(&config, broker.clone(), filter.clone());
+    start_listener(&config, broker.clone(), filter);
+    thread::sleep(Duration::from_millis(200));
+
+    // Handshake in Json, then send a compressed batch.
+    let mut stream = UnixStream::connect("/tmp/metrics_daemon_5").unwrap();
+    Frame::from_json(&json!({ "source": "test_source" }))
+        .write_to(&mut stream)
+        .unwrap();
+    let _ack = Frame::read_from(&mut stream).unwrap();
+    let _filter = Frame::read_from(&mut stream).unwrap();
+
+    let batch = json!([
+        { "seq_number": 1, "timestamp": 1, "payload": { "Name": "NE1" } },
+        { "seq_number": 2, "timestamp": 2, "payload": { "Name": "NE1" } }
+    ]);
+    Frame::encode(&batch, FrameType::Deflate)
+        .write_to(&mut stream)
+        .unwrap();
+
+    for seq_number in 1..3 {
+        let res = Frame::read_from(&mut stream).unwrap();
+        assert_eq!(res.typ, FrameType::Json);
+        let res: SuccessFrame = res.deserialize().unwrap();
+        assert!(res.success);
+        assert_eq!(res.seq_number, seq_number);
+    }
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+}
+
+#[test]
+fn test_protocol_negotiation() {
+    use frame_messages::default_shared_filterframe;
+    use message_broker::MessageBroker;
+    use std::time::Duration;
+
+    let config = Config {
+        socket_path: "/tmp/metrics_daemon_6".to_owned(),
+        relay_port: 54325,
+        ..Config::default()
+    };
+
+    let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
+    let filter = default_shared_filterframe();
+    ::queue::start_queue_manager(&config, broker.clone(), filter.clone());
+    start_listener(&config, broker.clone(), filter);
+    thread::sleep(Duration::from_millis(200));
+
+    // Clients from the future get our version.
+    let mut stream = UnixStream::connect("/tmp/metrics_daemon_6").unwrap();
+    Frame::from_json(&json!({
+        "source": "test_source",
+        "protocol_version": 7,
+        "capabilities": ["batch_ack", "teleportation"]
+    }))
+    .write_to(&mut stream)
+    .unwrap();
+    let ready: ReadyFrame = Frame::read_from(&mut stream)
+        .unwrap()
+        .deserialize()
+        .unwrap();
+    assert!(ready.ready);
+    assert_eq!(ready.protocol_version, PROTOCOL_VERSION);
+    assert!(ready.capabilities.contains(&"batch_ack".to_owned()));
+    let _filter = Frame::read_from(&mut stream).unwrap();
+
+    // The batch is acked once, after the errors.
+    let batch = json!([
+        { "seq_number": 1, "timestamp": 1, "payload": { "Name": "NE1" } },
+        { "seq_number": 2, "timestamp": 2, "payload": { "Name": "" } },
+        { "seq_number": 3, "timestamp": 3, "payload": { "Name": "NE1" } }
+    ]);
+    Frame::from_json(&batch).write_to(&mut stream).unwrap();
+    let res: ErrorFrame = Frame::read_from(&mut stream)
+        .unwrap()
+        .deserialize()
+        .unwrap();
+    assert!(!res.success);
+    assert_eq!(res.seq_number, 2);
+    let res: SuccessFrame = Frame::read_from(&mut stream)
+        .unwrap()
+        .deserialize()
+        .unwrap();
+    assert!(res.success);
+    assert_eq!(res.seq_number, 3);
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+}
+
+#[test]
+fn test_malformed_records() {
+    use frame_messages::default_shared_filterframe;
+    use message_broker::MessageBroker;
+    use serde_json;
+    use std::time::Duration;
+
+    let config = Config {
+        socket_path: "/tmp/metrics_daemon_7".to_owned(),
+        relay_port: 54326,
+        profiles: serde_json::from_value(json!({
+            "test": { "mcc_mnc": [46001], "bands": [1, 3] }
+        }))
+        .unwrap(),
+        ..Config::default()
+    };
+
+    let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
+    let filter = default_shared_filterframe();
+    ::queue::start_queue_manager(&config, broker.clone(), filter.clone());
+    start_listener(&config, broker.clone(), filter);
+    thread::sleep(Duration::from_millis(200));
+
+    let mut stream = UnixStream::connect("/tmp/metrics_daemon_7").unwrap();
+    Frame::from_json(&json!({ "source": "test_source" }))
+        .write_to(&mut stream)
+        .unwrap();
+    let _ready = Frame::read_from(&mut stream).unwrap();
+    let _filter = Frame::read_from(&mut stream).unwrap();
+
+    let batch = json!([
+        { "seq_number": 1, "timestamp": 1, "payload": { "Name": "NE1" } },
+        { "seq_number": 2, "payload": { "Name": "NE1" } },
+        { "seq_number": 3, "timestamp": -1, "payload": { "Name": "NE1" } },
+        { "seq_number": 4, "timestamp": 4, "payload": { "Name": ["NE1"] } },
+        { "seq_number": 5, "timestamp": 5, "payload": { "Name": "NE1" } },
+        { "seq_number": 6, "timestamp": 6, "payload": { "Name": "NE1", "LI1": 46001, "RI6": 40 } }
+    ]);
+    Frame::from_json(&batch).write_to(&mut stream).unwrap();
+
+    let expected = [
+        (1, None),
+        (2, Some("MissingTimestamp")),
+        (3, Some("InvalidTimestamp")),
+        (4, Some("InvalidJSON")),
+        (5, None),
+        (6, Some("InvalidRI6")),
+    ];
+    for &(seq_number, error) in &expected {
+        let res = Frame::read_from(&mut stream).unwrap().json().unwrap();
+        assert_eq!(res["seq_number"], json!(seq_number));
+        assert_eq!(res["success"], json!(error.is_none()));
+        if let Some(error) = error {
+            assert_eq!(res["error"], json!(error));
+        }
+        // Only the last record selects a profile.
+        let profile = if seq_number == 6 { json!("test") } else { Value::Null };
+        assert_eq!(res["profile"], profile);
+    }
+
+    // Records without a usable seq_number close the connection.
+    Frame::from_json(&json!([{ "timestamp": 6, "payload": { "Name": "NE1" } }]))
+        .write_to(&mut stream)
+        .unwrap();
+    assert!(Frame::read_from(&mut stream).is_err());
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+}
+
+#[test]
+fn test_graceful_shutdown() {
+    use frame_messages::default_shared_filterframe;
+    use message_broker::MessageBroker;
+    use mio::Events;
+    use serde_json;
+    use spool::Spool;
+    use std::time::Duration;
+
+    let dir =
+        ::std::env::temp_dir().join(format!("metrics_shutdown_spool_{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    // Nobody listens on the relay port, so items need to be spooled.
+    let config = Config {
+        socket_path: "/tmp/metrics_daemon_10".to_owned(),
+        relay_port: 54329,
+        spool_path: Some(dir.to_str().unwrap().to_owned()),
+        ..Config::default()
+    };
+
+    let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
+    let filter = default_shared_filterframe();
+    let queue = ::queue::start_queue_manager(&config, broker.clone(), filter.clone());
+    let poll = Poll::new().unwrap();
+    let mut listener = Listener::new(&config, broker.clone(), filter, &poll).unwrap();
+    let mut events = Events::with_capacity(16);
+
+    let mut stream = UnixStream::connect("/tmp/metrics_daemon_10").unwrap();
+    Frame::from_json(&json!({ "source": "test_source" }))
+        .write_to(&mut stream)
+        .unwrap();
+    for _ in 0..5 {
+        poll.poll(&mut events, Some(Duration::from_millis(50)))
+            .unwrap();
+        for event in events.iter() {
+            listener.ready(&poll, &event);
+        }
+    }
+    let _ready = Frame::read_from(&mut stream).unwrap();
+    let _filter = Frame::read_from(&mut stream).unwrap();
+
+    // This frame is only read once we are shutting down.
+    let msg: ClientMessage = serde_json::from_value(
+        json!({ "seq_number": 1, "timestamp": 1, "payload": { "Name": "NE1" } }),
+    )
+    .unwrap();
+    Frame::from_obj(&vec![msg]).write_to(&mut stream).unwrap();
+    listener.shutdown(&poll);
+    assert!(listener.is_done());
+    assert!(!Path::new("/tmp/metrics_daemon_10").exists());
+
+    let res: SuccessFrame = Frame::read_from(&mut stream)
+        .unwrap()
+        .deserialize()
+        .unwrap();
+    assert_eq!(res.seq_number, 1);
+    assert!(Frame::read_from(&mut stream).is_err());
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+    queue.join().unwrap();
+    let spool = Spool::open(&dir, 1024 * 1024, 100, 1024).unwrap();
+    assert_eq!(spool.len(), 1);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_socket_rebind() {
+    use frame_messages::default_shared_filterframe;
+    use message_broker::MessageBroker;
+    use std::time::Duration;
+
+    let config = Config {
+        socket_path: "/tmp/metrics_daemon_11".to_owned(),
+        relay_port: 54330,
+        ..Config::default()
+    };
+
+    let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
+    let filter = default_shared_filterframe();
+    ::queue::start_queue_manager(&config, broker.clone(), filter.clone());
+    let listener = start_listener(&config, broker.clone(), filter);
+    thread::sleep(Duration::from_millis(200));
+
+    let mut stream = UnixStream::connect("/tmp/metrics_daemon_11").unwrap();
+    Frame::from_json(&json!({ "source": "test_source" }))
+        .write_to(&mut stream)
+        .unwrap();
+    let _ready = Frame::read_from(&mut stream).unwrap();
+
+    let new_config = Config {
+        socket_path: "/tmp/metrics_daemon_11_new".to_owned(),
+        ..config
+    };
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::NewConfig(Box::new(new_config)));
+    thread::sleep(Duration::from_millis(200));
+    assert!(!Path::new("/tmp/metrics_daemon_11").exists());
+
+    // New clients use the new path, and connected ones are kept.
+    let mut stream2 = UnixStream::connect("/tmp/metrics_daemon_11_new").unwrap();
+    Frame::from_json(&json!({ "source": "test_source_2" }))
+        .write_to(&mut stream2)
+        .unwrap();
+    let ready: ReadyFrame = Frame::read_from(&mut stream2)
+        .unwrap()
+        .deserialize()
+        .unwrap();
+    assert!(ready.ready);
+    let _filter = Frame::read_from(&mut stream).unwrap();
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+    listener.join().unwrap();
+    assert!(!Path::new("/tmp/metrics_daemon_11_new").exists());
+}
+
+#[test]
+fn test_filter_backpressure() {
+    use frame_messages::{default_shared_filterframe, FilterFrame};
+    use message_broker::MessageBroker;
+    use std::time::Duration;
+
+    let config = Config {
+        socket_path: "/tmp/metrics_daemon_13".to_owned(),
+        relay_port: 54331,
+        ..Config::default()
+    };
+
+    let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
+    let filter = default_shared_filterframe();
+    ::queue::start_queue_manager(&config, broker.clone(), filter.clone());
+    let listener = start_listener(&config, broker.clone(), filter);
+    thread::sleep(Duration::from_millis(200));
+
+    let mut stream = UnixStream::connect("/tmp/metrics_daemon_13").unwrap();
+    Frame::from_json(&json!({ "source": "test_source" }))
+        .write_to(&mut stream)
+        .unwrap();
+    let _ready = Frame::read_from(&mut stream).unwrap();
+    let _filter = Frame::read_from(&mut stream).unwrap();
+
+    // More filters than the socket buffer holds, while the client doesn't read.
+    for ne in 1..20_001 {
+        let filter = FilterFrame { nc: 0, nd: 0, ne };
+        broker
+            .lock()
+            .unwrap()
+            .send_message("listener", InternalMessage::NewFilter(filter))
+            .unwrap();
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    // The pending output is flushed as the client reads, without any request.
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut last = 0;
+    while last < 20_000 {
+        let filter: FilterFrame = Frame::read_from(&mut stream)
+            .unwrap()
+            .deserialize()
+            .unwrap();
+        last = filter.ne;
+    }
+
+    // A client that never reads its replies is dropped once they pile up.
+    for ne in 1..60_001 {
+        let filter = FilterFrame { nc: 0, nd: 0, ne };
+        broker
+            .lock()
+            .unwrap()
+            .send_message("listener", InternalMessage::NewFilter(filter))
+            .unwrap();
+    }
+    thread::sleep(Duration::from_millis(500));
+    let mut last = 0;
+    while let Ok(frame) = Frame::read_from(&mut stream) {
+        last = frame.deserialize::<FilterFrame>().unwrap().ne;
+    }
+    assert!(last < 60_000);
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+    listener.join().unwrap();
+}
@@ -7,112 +7,300 @@
 
 #[cfg(target_os = "android")]
 extern crate android_logger;
-extern crate byteorder;
-extern crate chrono;
 #[cfg(not(target_os = "android"))]
 extern crate env_logger;
-#[macro_use]
-extern crate error_chain;
 extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate metrics_daemon;
 extern crate mio;
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
-#[macro_use]
-extern crate serde_json;
-
-pub mod config;
-pub mod frame;
-pub mod frame_messages;
-pub mod internal_messages;
-pub mod listener;
-pub mod message_broker;
-pub mod queue;
-pub mod socket_relay;
-
-use config::Config;
-use frame_messages::default_shared_filterframe;
-use internal_messages::InternalMessage;
-use libc::{getpid, sighandler_t, SIGINT};
-use message_broker::{MessageBroker, SharedMessageBroker};
+
+use libc::{getpid, sighandler_t, SIGHUP, SIGINT, SIGTERM};
+use metrics_daemon::admin;
+use metrics_daemon::cli::{Options, USAGE};
+use metrics_daemon::config::Config;
+use metrics_daemon::frame_messages::default_shared_filterframe;
+use metrics_daemon::internal_messages::InternalMessage;
+use metrics_daemon::listener::Listener;
+use metrics_daemon::message_broker::{MessageBroker, SharedMessageBroker};
+use metrics_daemon::queue;
+use metrics_daemon::stats;
 use mio::{Events, Poll};
 use std::env;
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-// Handle SIGINT (Ctrl-C) for manual shutdown.
+// Handle SIGINT (Ctrl-C) and SIGTERM for shutdown.
 // Signal handlers must not do anything substantial. To trigger shutdown, we atomically
 // flip this flag; the event loop checks the flag and exits accordingly.
-static SHUTDOWN_FLAG: AtomicBool = ATOMIC_BOOL_INIT;
+static SHUTDOWN_FLAG: AtomicBool = AtomicBool::new(false);
 unsafe fn handle_sigint(_: i32) {
     SHUTDOWN_FLAG.store(true, Ordering::Release);
 }
 
+// Handle SIGHUP to reload the configuration file, the same way.
+static RELOAD_FLAG: AtomicBool = AtomicBool::new(false);
+unsafe fn handle_sighup(_: i32) {
+    RELOAD_FLAG.store(true, Ordering::Release);
+}
+
+fn log_level(verbose: bool) -> log::LevelFilter {
+    if verbose {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Info
+    }
+}
+
+// The config properties that are applied without a restart.
+const LIVE_CHANGES: [&str; 7] = [
+    "buffer_size",
+    "enforce_filter",
+    "relay_address",
+    "relay_port",
+    "sink_events",
+    "socket_path",
+    "verbose",
+];
+
+// Loads the config file, with the command line overrides.
+fn load_config(options: &Options) -> metrics_daemon::config::Result<Config> {
+    let mut config = Config::load(Path::new(&options.config))?;
+    options.apply(&mut config)?;
+    Ok(config)
+}
+
+// Applies the changes of the config file to the running daemon. The threads
+// pick what they can apply live from the NewConfig message.
+fn reload_config(
+    options: &Options,
+    config: &mut Config,
+    broker: &SharedMessageBroker<InternalMessage>,
+) {
+    let path = &options.config;
+    let new_config = match load_config(options) {
+        Ok(new_config) => new_config,
+        Err(err) => {
+            error!("Keeping the current configuration: {}", err);
+            return;
+        }
+    };
+
+    let changes = config.changes(&new_config);
+    info!("Reloaded configuration file from {}, changes: {:?}", path, changes);
+    for name in &changes {
+        if !LIVE_CHANGES.contains(&name.as_str()) {
+            info!("The {} change will be applied on restart", name);
+        }
+    }
+    if new_config.verbose != config.verbose {
+        log::set_max_level(log_level(new_config.verbose));
+    }
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::NewConfig(Box::new(new_config.clone())));
+    *config = new_config;
+}
+
+// Waits for the threads to finish, but not after the deadline. Each thread is
+// joined from a helper thread that reports back, so that we can time out.
+fn join_threads(threads: Vec<(&'static str, JoinHandle<()>)>, deadline: Instant) {
+    let (tx, rx) = channel();
+    let mut pending: Vec<&str> = threads.iter().map(|&(name, _)| name).collect();
+    for (name, thread) in threads {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let _ = tx.send((name, thread.join().is_ok()));
+        });
+    }
+    while !pending.is_empty() {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        match rx.recv_timeout(deadline - now) {
+            Ok((name, joined)) => {
+                if !joined {
+                    error!("The {} thread panicked", name);
+                }
+                pending.retain(|&pending_name| pending_name != name);
+            }
+            Err(_) => break,
+        }
+    }
+    for name in pending {
+        error!("The {} thread didn't finish in time", name);
+    }
+}
+
 static VERSION : &'static str = include_str!("version.in");
 
+// The logger lets everything through, so that the admin socket can change
+// the level at runtime.
 #[cfg(target_os = "android")]
 fn init_logger(verbose: bool) {
     use android_logger::Filter;
     use log::Level;
 
-    let level = if verbose {
-        Filter::default()
-            .with_min_level(Level::Debug)
-    } else {
-        Filter::default()
-            .with_min_level(Level::Info)
-    };
-    android_logger::init_once(level, Some("MetricsDaemon"));
+    android_logger::init_once(
+        Filter::default().with_min_level(Level::Trace),
+        Some("MetricsDaemon"),
+    );
+    log::set_max_level(log_level(verbose));
 }
 
 #[cfg(not(target_os = "android"))]
-fn init_logger(_verbose: bool) {
-    env_logger::init();
+fn init_logger(verbose: bool) {
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Trace)
+        .init();
+    log::set_max_level(log_level(verbose));
 }
 
+// Android init supervises the daemon, so it always stays in the foreground.
+#[cfg(target_os = "android")]
+fn daemonize() {}
+
+// Detaches from the terminal: the parent exits, and the child gets its own
+// session with the standard streams on /dev/null.
+#[cfg(not(target_os = "android"))]
+fn daemonize() {
+    unsafe {
+        match libc::fork() {
+            -1 => {
+                eprintln!("Failed to detach: {}", std::io::Error::last_os_error());
+                process::exit(1);
+            }
+            0 => {}
+            _ => process::exit(0),
+        }
+        libc::setsid();
+        let null = libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_RDWR);
+        if null >= 0 {
+            for fd in 0..3 {
+                libc::dup2(null, fd);
+            }
+            if null > 2 {
+                libc::close(null);
+            }
+        }
+    }
+}
 
 fn main() {
     unsafe {
-        libc::signal(SIGINT, handle_sigint as sighandler_t);
+        libc::signal(SIGINT, handle_sigint as *const () as sighandler_t);
+        libc::signal(SIGTERM, handle_sigint as *const () as sighandler_t);
+        libc::signal(SIGHUP, handle_sighup as *const () as sighandler_t);
+    }
+
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            process::exit(1);
+        }
+    };
+    if options.help {
+        println!("{}", USAGE);
+        return;
+    }
+    if options.version {
+        println!("{}", VERSION.trim());
+        return;
     }
 
-    let cpath = match env::args().skip(1).next() {
-        Some(val) => val,
-        None => "./config.json".to_owned(),
+    // The logger is up before the config is loaded, so that its warnings show.
+    init_logger(options.verbose);
+    let mut config = match load_config(&options) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Failed to load the configuration from {}: {}", options.config, err);
+            process::exit(1);
+        }
     };
+    log::set_max_level(log_level(config.verbose));
+    if options.check_config {
+        println!("The configuration in {} is valid", options.config);
+        return;
+    }
 
-    
-    let config = Config::load(&PathBuf::from(cpath.clone()));
-    init_logger(config.verbose);
-    
-    info!("Starting metrics daemon {}, pid is {}", VERSION, unsafe { getpid() });
-    info!("Loaded configuration file from {}, verbose mode: {}", cpath, config.verbose);
+    if options.daemonize {
+        daemonize();
+    }
 
+    let pid = unsafe { getpid() };
+    if let Some(ref pidfile) = options.pidfile {
+        if let Err(err) = fs::write(pidfile, format!("{}\n", pid)) {
+            error!("Failed to write the pid file {}: {}", pidfile, err);
+            process::exit(1);
+        }
+    }
+    info!("Starting metrics daemon {}, pid is {}", VERSION, pid);
+    info!(
+        "Loaded configuration file from {}, verbose mode: {}",
+        options.config, config.verbose
+    );
 
     let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
     // Start with the default filter.
     let filter = default_shared_filterframe();
 
-    queue::start_queue_manager(&config, broker.clone(), filter.clone());
-    listener::start_listener(&config, broker.clone(), filter);
+    let mut threads = vec![(
+        "queue",
+        queue::start_queue_manager(&config, broker.clone(), filter.clone()),
+    )];
+    if let Some(thread) = admin::start_admin(&config, broker.clone(), filter.clone()) {
+        threads.push(("admin", thread));
+    }
+    if let Some(thread) = stats::start_stats(&config, broker.clone()) {
+        threads.push(("stats", thread));
+    }
 
-    // Simple event loop, just waiting for SIGINT to exit.
+    // The client listener runs on the main event loop, which also checks for SIGINT.
     let poll = Poll::new().unwrap();
+    let mut listener = match Listener::new(&config, broker.clone(), filter, &poll) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Failed to start the client listener: {}", err);
+            if let Some(ref pidfile) = options.pidfile {
+                let _ = fs::remove_file(pidfile);
+            }
+            process::exit(1);
+        }
+    };
     let mut events = Events::with_capacity(1024);
     loop {
-        let _ = poll.poll(&mut events, None);
+        // Signals may be delivered to another thread, so don't block forever.
+        let _ = poll.poll(&mut events, Some(Duration::from_millis(500)));
         if SHUTDOWN_FLAG.load(Ordering::Acquire) {
             break;
         }
+        if RELOAD_FLAG.swap(false, Ordering::AcqRel) {
+            reload_config(&options, &mut config, &broker);
+        }
+        for event in events.iter() {
+            listener.ready(&poll, &event);
+        }
     }
 
     info!("Starting shutdown of metrics daemon");
+    let deadline = Instant::now() + Duration::from_secs(config.shutdown_timeout);
+    // The frames we already got reach the queue before the Shutdown message.
+    listener.shutdown(&poll);
     broker
         .lock()
         .unwrap()
         .broadcast_message(InternalMessage::Shutdown);
-    ::std::thread::sleep(::std::time::Duration::new(1, 0));
+    join_threads(threads, deadline);
+    if let Some(ref pidfile) = options.pidfile {
+        let _ = fs::remove_file(pidfile);
+    }
     info!("Shutdown complete.");
 }
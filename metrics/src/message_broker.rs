@@ -103,7 +103,13 @@ impl<T> MessageBroker<T> {
         let actors = &self.actors;
         for (target, actor) in actors {
             debug!("Sending {:?} to {}", message.clone(), target);
-            actor.send(message.clone()).expect("Failed to send message");
+            // An actor that went away must not prevent others from getting the message.
+            if actor.send(message.clone()).is_err() {
+                error!(
+                    "MessageBroker::broadcast_message: error sending `{:?}` to `{}`",
+                    message, target
+                );
+            }
         }
     }
 }
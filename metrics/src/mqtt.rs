@@ -0,0 +1,569 @@
+// (c) 2017 KAI OS TECHNOLOGIES (HONG KONG) LIMITED All rights reserved. This
+// file or any portion thereof may not be reproduced or used in any manner
+// whatsoever without the express written permission of KAI OS TECHNOLOGIES
+// (HONG KONG) LIMITED. KaiOS is the trademark of KAI OS TECHNOLOGIES (HONG KONG)
+// LIMITED or its affiliate company and may be registered in some jurisdictions.
+// All other trademarks are the property of their respective owners.
+
+/// Minimal MQTT 3.1.1 client used to publish relayed items to `mqtt_host`.
+/// Only what we need is implemented: CONNECT, QoS 1 PUBLISH with PUBACK
+/// tracking, keepalive pings and DISCONNECT.
+use config::Config;
+use serde::Serialize;
+use serde_json;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+error_chain!{
+    errors {
+        ConnectionRefused(code: u8) {
+            description("Connection refused by the MQTT broker")
+            display("Connection refused by the MQTT broker, return code {}", code)
+        }
+
+        Protocol(s: String) {
+            description("MQTT protocol error")
+            display("MQTT protocol error: {}", s)
+        }
+    }
+
+    foreign_links {
+        Io(::std::io::Error);
+    }
+}
+
+// Control packet types, already shifted in the upper nibble of the fixed header.
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+const DISCONNECT: u8 = 0xE0;
+
+// Flags of a QoS 1 PUBLISH packet.
+const QOS1: u8 = 0x02;
+const DUP: u8 = 0x08;
+
+// How many PUBLISH packets can wait for their PUBACK at the same time.
+const MAX_INFLIGHT: usize = 16;
+
+// Upper bound of the reconnection backoff, in seconds.
+const MAX_RECONNECT_DELAY: u64 = 10;
+
+// How long connecting, waiting for CONNACK or writing may block the publisher
+// thread, which then doesn't see the Shutdown command.
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Connects to the first address of `host` that answers in time.
+fn connect_stream(host: &str) -> Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "No address for the MQTT host");
+    for address in host.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, NETWORK_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err.into())
+}
+
+fn write_remaining_length(mut len: usize, buf: &mut Vec<u8>) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        buf.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn write_string(s: &str, buf: &mut Vec<u8>) {
+    buf.push((s.len() >> 8) as u8);
+    buf.push(s.len() as u8);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut buf = vec![header];
+    write_remaining_length(body.len(), &mut buf);
+    buf.extend_from_slice(body);
+    buf
+}
+
+pub fn connect_packet(client_id: &str, keep_alive: u16) -> Vec<u8> {
+    let mut body = vec![];
+    write_string("MQTT", &mut body);
+    body.push(4); // Protocol level 3.1.1
+    body.push(0x02); // Clean session, no will, no credentials.
+    body.push((keep_alive >> 8) as u8);
+    body.push(keep_alive as u8);
+    write_string(client_id, &mut body);
+    packet(CONNECT, &body)
+}
+
+pub fn publish_packet(topic: &str, packet_id: u16, payload: &[u8], dup: bool) -> Vec<u8> {
+    let mut body = vec![];
+    write_string(topic, &mut body);
+    body.push((packet_id >> 8) as u8);
+    body.push(packet_id as u8);
+    body.extend_from_slice(payload);
+    let flags = if dup { QOS1 | DUP } else { QOS1 };
+    packet(PUBLISH | flags, &body)
+}
+
+/// Reads a full control packet, returning its fixed header byte and its body.
+pub fn read_packet<T: Read>(source: &mut T) -> Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 1];
+    source.read_exact(&mut header)?;
+
+    let mut len = 0usize;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0u8; 1];
+        source.read_exact(&mut byte)?;
+        len += ((byte[0] & 0x7F) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift > 21 {
+            bail!(ErrorKind::Protocol("Malformed remaining length".into()));
+        }
+    }
+
+    let mut body = vec![0u8; len];
+    source.read_exact(&mut body)?;
+    Ok((header[0], body))
+}
+
+#[derive(Clone)]
+struct Publish {
+    topic: String,
+    payload: Vec<u8>,
+}
+
+enum Command {
+    Publish(Publish),
+    PubAck(usize, u16),
+    PingResp(usize),
+    Closed(usize),
+    Shutdown,
+}
+
+struct Connection {
+    id: usize,
+    stream: TcpStream,
+    last_write: Instant,
+    ping_sent: Option<Instant>,
+}
+
+impl Connection {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.stream.write_all(data)?;
+        self.stream.flush()?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+}
+
+// Reads the incoming packets of a connection and forwards the ones we care about
+// to the publisher thread.
+fn read_loop(id: usize, mut stream: TcpStream, commands: Sender<Command>) {
+    loop {
+        let command = match read_packet(&mut stream) {
+            Ok((header, ref body)) if header & 0xF0 == PUBACK && body.len() == 2 => {
+                Command::PubAck(id, (body[0] as u16) << 8 | body[1] as u16)
+            }
+            Ok((header, _)) if header & 0xF0 == PINGRESP => Command::PingResp(id),
+            Ok((header, _)) => {
+                debug!("Ignoring MQTT packet with header {:#x}", header);
+                continue;
+            }
+            Err(err) => {
+                debug!("MQTT connection {} closed: {}", id, err);
+                Command::Closed(id)
+            }
+        };
+        let closed = matches!(command, Command::Closed(_));
+        if commands.send(command).is_err() || closed {
+            return;
+        }
+    }
+}
+
+struct PublisherState {
+    host: String,
+    client_id: String,
+    keep_alive: u16,
+    max_pending: usize,
+    commands: Sender<Command>,
+    connection: Option<Connection>,
+    connection_count: usize,
+    pending: VecDeque<Publish>,
+    inflight: BTreeMap<u16, Publish>,
+    next_packet_id: u16,
+}
+
+impl PublisherState {
+    fn connect(&mut self) -> Result<()> {
+        debug!("Connecting to MQTT broker at {}", self.host);
+        let mut stream = connect_stream(&self.host)?;
+        stream.set_read_timeout(Some(NETWORK_TIMEOUT))?;
+        stream.set_write_timeout(Some(NETWORK_TIMEOUT))?;
+        stream.write_all(&connect_packet(&self.client_id, self.keep_alive))?;
+        let (header, body) = read_packet(&mut stream)?;
+        if header != CONNACK || body.len() != 2 {
+            bail!(ErrorKind::Protocol(format!(
+                "Expected CONNACK, got header {:#x}",
+                header
+            )));
+        }
+        if body[1] != 0 {
+            bail!(ErrorKind::ConnectionRefused(body[1]));
+        }
+        stream.set_read_timeout(None)?;
+
+        self.connection_count += 1;
+        let id = self.connection_count;
+        let reader = stream.try_clone()?;
+        let commands = self.commands.clone();
+        thread::Builder::new()
+            .name("mqtt reader".to_owned())
+            .spawn(move || read_loop(id, reader, commands))?;
+
+        info!("Connected to MQTT broker at {}", self.host);
+        self.connection = Some(Connection {
+            id,
+            stream,
+            last_write: Instant::now(),
+            ping_sent: None,
+        });
+
+        // Anything that was not acknowledged on the previous connection is sent again.
+        let inflight: Vec<(u16, Publish)> = self
+            .inflight
+            .iter()
+            .map(|(id, publish)| (*id, publish.clone()))
+            .collect();
+        for (id, publish) in inflight {
+            debug!("Resending unacknowledged MQTT packet {}", id);
+            self.write(&publish_packet(&publish.topic, id, &publish.payload, true));
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            info!("Dropping MQTT connection {}", connection.id);
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        let failed = match self.connection {
+            Some(ref mut connection) => connection.write(data).is_err(),
+            None => false,
+        };
+        if failed {
+            error!("Failed to write to the MQTT broker");
+            self.disconnect();
+        }
+    }
+
+    fn enqueue(&mut self, publish: Publish) {
+        if self.pending.len() >= self.max_pending {
+            info!("MQTT queue overflow, removing element");
+            self.pending.pop_front();
+        }
+        self.pending.push_back(publish);
+    }
+
+    fn allocate_packet_id(&mut self) -> u16 {
+        loop {
+            self.next_packet_id = self.next_packet_id.wrapping_add(1);
+            if self.next_packet_id != 0 && !self.inflight.contains_key(&self.next_packet_id) {
+                return self.next_packet_id;
+            }
+        }
+    }
+
+    // Sends pending messages while the in-flight window allows it.
+    fn flush(&mut self) {
+        while self.connection.is_some() && self.inflight.len() < MAX_INFLIGHT {
+            let publish = match self.pending.pop_front() {
+                Some(publish) => publish,
+                None => return,
+            };
+            let id = self.allocate_packet_id();
+            self.write(&publish_packet(&publish.topic, id, &publish.payload, false));
+            self.inflight.insert(id, publish);
+        }
+    }
+
+    // Sends a PINGREQ when idle, and gives up on the connection when the
+    // broker doesn't answer it within the keepalive period.
+    fn keep_alive(&mut self) {
+        let keep_alive = Duration::new(self.keep_alive as u64, 0);
+        let (ping, expired) = match self.connection {
+            Some(ref connection) => match connection.ping_sent {
+                Some(sent) => (false, sent.elapsed() >= keep_alive),
+                None => (connection.last_write.elapsed() >= keep_alive / 2, false),
+            },
+            None => return,
+        };
+        if expired {
+            error!("MQTT broker did not answer our PINGREQ");
+            self.disconnect();
+        } else if ping {
+            self.write(&[PINGREQ, 0]);
+            if let Some(ref mut connection) = self.connection {
+                connection.ping_sent = Some(Instant::now());
+            }
+        }
+    }
+
+    fn is_current(&self, id: usize) -> bool {
+        match self.connection {
+            Some(ref connection) => connection.id == id,
+            None => false,
+        }
+    }
+
+    fn run(&mut self, commands: Receiver<Command>) {
+        let mut delay = 1u64;
+        let mut next_attempt = Instant::now();
+        loop {
+            if self.connection.is_none() && Instant::now() >= next_attempt {
+                match self.connect() {
+                    Ok(()) => delay = 1,
+                    Err(err) => {
+                        debug!("MQTT connection failed: {}", err);
+                        next_attempt = Instant::now() + Duration::new(delay, 0);
+                        delay = ::std::cmp::min(delay * 2, MAX_RECONNECT_DELAY);
+                    }
+                }
+            }
+            self.flush();
+
+            let timeout = if self.connection.is_some() {
+                Duration::from_millis(self.keep_alive as u64 * 250)
+            } else {
+                next_attempt.saturating_duration_since(Instant::now())
+            };
+            match commands.recv_timeout(timeout) {
+                Ok(Command::Publish(publish)) => self.enqueue(publish),
+                Ok(Command::PubAck(id, packet_id)) => {
+                    if self.is_current(id) && self.inflight.remove(&packet_id).is_none() {
+                        debug!("Unexpected PUBACK for packet {}", packet_id);
+                    }
+                }
+                Ok(Command::PingResp(id)) => {
+                    if let Some(ref mut connection) = self.connection {
+                        if connection.id == id {
+                            connection.ping_sent = None;
+                        }
+                    }
+                }
+                Ok(Command::Closed(id)) => {
+                    if self.is_current(id) {
+                        self.disconnect();
+                    }
+                }
+                Ok(Command::Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+                    info!("Shutting down MQTT publisher");
+                    self.write(&[DISCONNECT, 0]);
+                    self.disconnect();
+                    return;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+            self.keep_alive();
+        }
+    }
+}
+
+/// Handle to the MQTT publisher thread.
+pub struct MqttPublisher {
+    commands: Sender<Command>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MqttPublisher {
+    pub fn start(config: &Config) -> Self {
+        let (tx, rx) = channel::<Command>();
+        let mut state = PublisherState {
+            host: config.mqtt_host.clone(),
+            client_id: config.mqtt_client_id.clone(),
+            keep_alive: ::std::cmp::max(config.mqtt_keep_alive, 1),
+            max_pending: config.buffer_size,
+            commands: tx.clone(),
+            connection: None,
+            connection_count: 0,
+            pending: VecDeque::new(),
+            inflight: BTreeMap::new(),
+            next_packet_id: 0,
+        };
+
+        let thread = thread::Builder::new()
+            .name("mqtt publisher".to_owned())
+            .spawn(move || state.run(rx))
+            .expect("Failed to create mqtt publisher thread");
+
+        MqttPublisher {
+            commands: tx,
+            thread: Some(thread),
+        }
+    }
+
+    /// Queues a JSON serialized payload to be published on `topic`.
+    pub fn publish<T: Serialize>(&self, topic: &str, payload: &T) {
+        let publish = Publish {
+            topic: topic.to_owned(),
+            payload: serde_json::to_vec(payload).unwrap(),
+        };
+        if self.commands.send(Command::Publish(publish)).is_err() {
+            error!("MQTT publisher thread is gone");
+        }
+    }
+
+    /// Disconnects from the broker and waits for the publisher thread to exit.
+    pub fn shutdown(mut self) {
+        let _ = self.commands.send(Command::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+fn accept_mqtt_client(listener: &::std::net::TcpListener) -> TcpStream {
+    let (mut stream, _) = listener.accept().unwrap();
+    let (header, body) = read_packet(&mut stream).unwrap();
+    assert_eq!(header, CONNECT);
+    assert_eq!(&body[0..7], b"\x00\x04MQTT\x04");
+    stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+    stream
+}
+
+#[cfg(test)]
+fn read_publish(stream: &mut TcpStream) -> (u8, String, u16, Vec<u8>) {
+    let (header, body) = read_packet(stream).unwrap();
+    assert_eq!(header & 0xF0, PUBLISH);
+    let topic_len = (body[0] as usize) << 8 | body[1] as usize;
+    let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+    let id = (body[2 + topic_len] as u16) << 8 | body[3 + topic_len] as u16;
+    (header, topic, id, body[4 + topic_len..].to_vec())
+}
+
+#[cfg(test)]
+fn test_config(port: u16) -> Config {
+    Config {
+        mqtt_host: format!("127.0.0.1:{}", port),
+        mqtt_enabled: true,
+        ..Config::default()
+    }
+}
+
+#[test]
+fn test_remaining_length() {
+    let mut buf = vec![];
+    write_remaining_length(0, &mut buf);
+    write_remaining_length(127, &mut buf);
+    write_remaining_length(128, &mut buf);
+    write_remaining_length(16_383, &mut buf);
+    write_remaining_length(2_097_152, &mut buf);
+    assert_eq!(
+        buf,
+        vec![0x00, 0x7F, 0x80, 0x01, 0xFF, 0x7F, 0x80, 0x80, 0x80, 0x01]
+    );
+
+    let mut source: &[u8] = &[PUBACK, 0x80, 0x01];
+    assert!(read_packet(&mut source).is_err());
+}
+
+#[test]
+fn test_mqtt_publish() {
+    use frame_messages::FilterAck;
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let publisher = MqttPublisher::start(&test_config(port));
+    publisher.publish("metrics/filter_ack", &FilterAck::default());
+
+    let mut stream = accept_mqtt_client(&listener);
+    let (header, topic, id, payload) = read_publish(&mut stream);
+    assert_eq!(header, PUBLISH | QOS1);
+    assert_eq!(topic, "metrics/filter_ack");
+    assert_eq!(payload, br#"{"kind":"FilterAck","success":true}"#.to_vec());
+    stream.write_all(&[PUBACK, 2, (id >> 8) as u8, id as u8]).unwrap();
+
+    publisher.shutdown();
+    let (header, _) = read_packet(&mut stream).unwrap();
+    assert_eq!(header, DISCONNECT);
+}
+
+#[test]
+fn test_mqtt_resend_after_reconnect() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let publisher = MqttPublisher::start(&test_config(port));
+    publisher.publish("metrics/payload", &json!({ "Name": "NE1" }));
+
+    // Drop the connection without acknowledging the packet.
+    {
+        let mut stream = accept_mqtt_client(&listener);
+        let (header, _, _, _) = read_publish(&mut stream);
+        assert_eq!(header, PUBLISH | QOS1);
+        stream.shutdown(Shutdown::Both).unwrap();
+    }
+
+    // The publisher reconnects and sends the packet again as a duplicate.
+    let mut stream = accept_mqtt_client(&listener);
+    let (header, topic, id, payload) = read_publish(&mut stream);
+    assert_eq!(header, PUBLISH | QOS1 | DUP);
+    assert_eq!(topic, "metrics/payload");
+    assert_eq!(payload, br#"{"Name":"NE1"}"#.to_vec());
+    stream.write_all(&[PUBACK, 2, (id >> 8) as u8, id as u8]).unwrap();
+
+    // New packets go through the new connection.
+    publisher.publish("metrics/payload", &json!({ "Name": "NE2" }));
+    let (header, _, next_id, payload) = read_publish(&mut stream);
+    assert_eq!(header, PUBLISH | QOS1);
+    assert_ne!(next_id, id);
+    assert_eq!(payload, br#"{"Name":"NE2"}"#.to_vec());
+
+    publisher.shutdown();
+}
+
+#[test]
+fn test_mqtt_keep_alive() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut config = test_config(port);
+    config.mqtt_keep_alive = 1;
+    let publisher = MqttPublisher::start(&config);
+
+    let mut stream = accept_mqtt_client(&listener);
+    stream
+        .set_read_timeout(Some(Duration::new(5, 0)))
+        .unwrap();
+    let (header, body) = read_packet(&mut stream).unwrap();
+    assert_eq!(header, PINGREQ);
+    assert!(body.is_empty());
+    stream.write_all(&[PINGRESP, 0]).unwrap();
+
+    publisher.shutdown();
+}
@@ -0,0 +1,196 @@
+// (c) 2017 KAI OS TECHNOLOGIES (HONG KONG) LIMITED All rights reserved. This
+// file or any portion thereof may not be reproduced or used in any manner
+// whatsoever without the express written permission of KAI OS TECHNOLOGIES
+// (HONG KONG) LIMITED. KaiOS is the trademark of KAI OS TECHNOLOGIES (HONG KONG)
+// LIMITED or its affiliate company and may be registered in some jurisdictions.
+// All other trademarks are the property of their respective owners.
+
+/// Scrubs personal data from the payloads before they leave the device.
+///
+/// Each payload field can have a policy. Fields whose value doesn't fit their
+/// policy, like a hashed number, are dropped rather than sent verbatim.
+use config::Config;
+use frame_messages::ClientPayload;
+use hmac_sha256::HMAC;
+use serde_json::{self, Map, Value};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+const SALT_SIZE: usize = 32;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum Policy {
+    Drop,                      // Never send the field.
+    Hash,                      // Replace strings by their keyed hash, as hex.
+    Truncate { length: usize }, // Keep the first characters of strings.
+    Grid { size: f64 },        // Move coordinates to the center of their grid cell.
+}
+
+pub type Policies = BTreeMap<String, Policy>;
+
+pub struct Privacy {
+    policies: Policies,
+    salt: Vec<u8>,
+}
+
+fn random_salt() -> Vec<u8> {
+    let mut salt = vec![0u8; SALT_SIZE];
+    File::open("/dev/urandom")
+        .and_then(|mut file| file.read_exact(&mut salt))
+        .expect("Can't read /dev/urandom");
+    salt
+}
+
+// Reads the salt, creating it on first use. The salt never leaves the device,
+// so the hashes can't be reversed by the backend.
+fn load_salt(path: &str) -> Vec<u8> {
+    if let Ok(salt) = fs::read(path) {
+        if !salt.is_empty() {
+            return salt;
+        }
+    }
+
+    let salt = random_salt();
+    let res = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(&salt));
+    if let Err(err) = res {
+        // Hashes will change on restart, which is better than no hash.
+        error!("Failed to save the privacy salt to {}: {}", path, err);
+    }
+    salt
+}
+
+impl Privacy {
+    pub fn new(config: &Config) -> Self {
+        let salt = if config.privacy.is_empty() {
+            vec![]
+        } else {
+            match config.privacy_salt_path {
+                Some(ref path) => load_salt(path),
+                None => random_salt(),
+            }
+        };
+
+        Privacy {
+            policies: config.privacy.clone(),
+            salt,
+        }
+    }
+
+    fn hash(&self, value: &str) -> String {
+        HMAC::mac(value.as_bytes(), &self.salt)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    // Returns None if the field needs to be dropped.
+    fn scrub(&self, policy: &Policy, value: Value) -> Option<Value> {
+        match (policy, value) {
+            (&Policy::Drop, _) => None,
+            (&Policy::Hash, Value::String(s)) => Some(Value::String(self.hash(&s))),
+            (&Policy::Truncate { length }, Value::String(s)) => {
+                Some(Value::String(s.chars().take(length).collect()))
+            }
+            (&Policy::Grid { size }, Value::Number(n)) => n
+                .as_f64()
+                .map(|n| ((n / size).floor() + 0.5) * size)
+                .and_then(|n| serde_json::Number::from_f64(n).map(Value::Number)),
+            _ => None,
+        }
+    }
+
+    fn scrub_fields(&self, fields: &mut Map<String, Value>) {
+        for (field, policy) in &self.policies {
+            if let Some(value) = fields.remove(field) {
+                if value.is_null() {
+                    continue;
+                }
+                match self.scrub(policy, value) {
+                    Some(value) => {
+                        fields.insert(field.clone(), value);
+                    }
+                    None => debug!("Dropping {} from the payload", field),
+                }
+            }
+        }
+    }
+
+    /// Applies the policies, returning None if the payload can't be scrubbed.
+    pub fn apply(&self, payload: ClientPayload) -> Option<ClientPayload> {
+        if self.policies.is_empty() {
+            return Some(payload);
+        }
+
+        let mut fields = match serde_json::to_value(&payload) {
+            Ok(Value::Object(fields)) => fields,
+            _ => return None,
+        };
+        self.scrub_fields(&mut fields);
+        serde_json::from_value(Value::Object(fields)).ok()
+    }
+}
+
+#[test]
+fn scrub_payload() {
+    let config: Config = serde_json::from_value(json!({
+        "socket_path": "/tmp/metricsd_socket",
+        "mqtt_host": "localhost:1883",
+        "buffer_size": 10,
+        "relay_port": 12345,
+        "verbose": false,
+        "privacy": {
+            "DI1": { "policy": "truncate", "length": 8 },
+            "DI2": { "policy": "drop" },
+            "DI3": { "policy": "hash" },
+            "LI3": { "policy": "hash" },
+            "LI5": { "policy": "grid", "size": 0.5 },
+            "LI6": { "policy": "grid", "size": 0.5 }
+        }
+    }))
+    .unwrap();
+    let privacy = Privacy::new(&config);
+
+    let payload: ClientPayload = serde_json::from_value(json!({
+        "Name": "NE1",
+        "DI1": "356938035643809",
+        "DI2": "404450123456789",
+        "DI3": "+919876543210",
+        "LI3": 56789,
+        "LI5": 22.5726,
+        "LI6": -88.3639
+    }))
+    .unwrap();
+    let payload = privacy.apply(payload).unwrap();
+    let fields = serde_json::to_value(&payload).unwrap();
+
+    assert_eq!(fields["DI1"], json!("35693803"));
+    assert!(fields.get("DI2").is_none());
+    assert_eq!(fields["DI3"], json!(privacy.hash("+919876543210")));
+    assert_eq!(fields["DI3"].as_str().unwrap().len(), 64);
+    // Numbers can't be hashed, so they are dropped.
+    assert!(fields.get("LI3").is_none());
+    assert_eq!(fields["LI5"], json!(22.75));
+    assert_eq!(fields["LI6"], json!(-88.25));
+}
+
+#[test]
+fn persistent_salt() {
+    let path =
+        ::std::env::temp_dir().join(format!("metrics_privacy_salt_{}", ::std::process::id()));
+    let path = path.to_str().unwrap();
+    let _ = fs::remove_file(path);
+
+    let salt = load_salt(path);
+    assert_eq!(salt.len(), SALT_SIZE);
+    assert_eq!(load_salt(path), salt);
+    let _ = fs::remove_file(path);
+}
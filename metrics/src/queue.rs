@@ -6,19 +6,41 @@
 // All other trademarks are the property of their respective owners.
 
 /// Message queue manager.
-use config::Config;
-use frame_messages::{ClientPayload, FilterAck, SharedFilterFrame};
+use admin::{self, AdminRequest};
+use aggregation::{Aggregator, CounterSummary};
+use config::{Config, RelayAddress};
+use file_sink::FileLogger;
+use frame_messages::{ClientPayload, FilterAck, FilterStats, SharedFilterFrame};
 use internal_messages::InternalMessage;
 use message_broker::SharedMessageBroker;
+use mqtt::MqttPublisher;
+use privacy::Privacy;
+use rollup::{CellRollup, Rollup};
 use serde::{Serialize, Serializer};
-use socket_relay::{start_relay, SocketRelay};
-use std::collections::VecDeque;
-use std::sync::mpsc::channel;
-use std::thread;
+use serde_json;
+use socket_relay::{self, start_relay, RelayConnector, RelayWriter, SocketRelay};
+use spool::Spool;
+use stats::STATS;
+use std::collections::{BTreeSet, VecDeque};
+use std::fs::File;
+use std::io::Read;
+use std::result::Result as StdResult;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+// How long we wait before relaying again to a consumer that is behind.
+const RELAY_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// Items are spooled as their JSON serialization. Only ClientPayload has a
+// `Name`, and the other items are told apart by their mandatory fields.
+#[derive(Deserialize)]
+#[serde(untagged)]
 enum QueueItem {
-    ClientPayload(ClientPayload),
+    ClientPayload(Box<ClientPayload>),
     FilterAck(FilterAck),
+    CounterSummary(Box<CounterSummary>),
+    CellRollup(Box<CellRollup>),
 }
 
 impl Serialize for QueueItem {
@@ -29,87 +51,1259 @@ impl Serialize for QueueItem {
         match *self {
             QueueItem::ClientPayload(ref val) => val.serialize(serializer),
             QueueItem::FilterAck(ref val) => val.serialize(serializer),
+            QueueItem::CounterSummary(ref val) => val.serialize(serializer),
+            QueueItem::CellRollup(ref val) => val.serialize(serializer),
+        }
+    }
+}
+
+impl QueueItem {
+    // The name that sink_events filter on.
+    fn event_name(&self) -> &str {
+        match *self {
+            QueueItem::ClientPayload(ref val) => &val.name,
+            QueueItem::FilterAck(_) => "FilterAck",
+            QueueItem::CounterSummary(_) => "CounterSummary",
+            QueueItem::CellRollup(_) => "CellRollup",
+        }
+    }
+}
+
+// A destination of the items besides the relay, which the queue manager
+// drives itself. Sinks buffer on their own thread, so delivering never
+// blocks the queue.
+trait Sink: Send {
+    fn deliver(&mut self, item: &QueueItem);
+    fn shutdown(self: Box<Self>);
+}
+
+struct MqttSink {
+    publisher: MqttPublisher,
+    payload_topic: String,
+    ack_topic: String,
+}
+
+impl Sink for MqttSink {
+    fn deliver(&mut self, item: &QueueItem) {
+        let topic = match *item {
+            QueueItem::FilterAck(_) => &self.ack_topic,
+            _ => &self.payload_topic,
+        };
+        self.publisher.publish(topic, item);
+    }
+
+    fn shutdown(self: Box<Self>) {
+        self.publisher.shutdown();
+    }
+}
+
+impl Sink for FileLogger {
+    fn deliver(&mut self, item: &QueueItem) {
+        self.log(item);
+    }
+
+    fn shutdown(self: Box<Self>) {
+        FileLogger::shutdown(*self);
+    }
+}
+
+// The event names a sink gets, or None for all of them.
+fn sink_events(config: &Config, name: &str) -> Option<BTreeSet<String>> {
+    config
+        .sink_events
+        .get(name)
+        .map(|events| events.iter().cloned().collect())
+}
+
+fn accepts(events: &Option<BTreeSet<String>>, item: &QueueItem) -> bool {
+    match *events {
+        Some(ref events) => events.contains(item.event_name()),
+        None => true,
+    }
+}
+
+struct SinkEntry {
+    name: &'static str,
+    events: Option<BTreeSet<String>>,
+    sink: Box<dyn Sink>,
+}
+
+// The sinks enabled in the config, besides the relay.
+fn open_sinks(config: &Config) -> Vec<SinkEntry> {
+    let mut sinks = vec![];
+    if config.mqtt_enabled {
+        sinks.push(SinkEntry {
+            name: "mqtt",
+            events: sink_events(config, "mqtt"),
+            sink: Box::new(MqttSink {
+                publisher: MqttPublisher::start(config),
+                payload_topic: config.mqtt_payload_topic.clone(),
+                ack_topic: config.mqtt_ack_topic.clone(),
+            }),
+        });
+    }
+    if let Some(ref path) = config.file_sink_path {
+        sinks.push(SinkEntry {
+            name: "file",
+            events: sink_events(config, "file"),
+            sink: Box::new(FileLogger::start(path, config.buffer_size)),
+        });
+    }
+    sinks
+}
+
+fn open_spool(config: &Config) -> Option<Spool> {
+    let path = match config.spool_path {
+        Some(ref path) => path,
+        None => return None,
+    };
+    match Spool::open(
+        path,
+        config.spool_max_bytes,
+        config.spool_max_records,
+        config.spool_segment_size,
+    ) {
+        Ok(spool) => Some(spool),
+        Err(err) => {
+            error!("Failed to open spool at {}: {}", path, err);
+            None
+        }
+    }
+}
+
+// The relay sequence ids start again at 1 on each run, so the records carry a
+// random session id that lets the consumer tell them from the previous ones.
+fn new_relay_session() -> String {
+    let mut id = [0u8; 8];
+    File::open("/dev/urandom")
+        .and_then(|mut file| file.read_exact(&mut id))
+        .expect("Can't read /dev/urandom");
+    id.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+struct QueueManager {
+    config: Config,
+    broker: SharedMessageBroker<InternalMessage>,
+    filter: SharedFilterFrame,
+    max_queue_size: usize,
+    queue: VecDeque<QueueItem>,
+    spool: Option<Spool>,
+    relay: Option<RelayWriter>,
+    connector: Option<RelayConnector>,
+    last_address: Option<RelayAddress>, // Where the last relay connection went.
+    retry_drain: Option<Instant>, // When to relay again if the consumer is behind.
+    filter_stats: FilterStats,
+    privacy: Privacy,
+    aggregator: Aggregator,
+    rollup: Rollup,
+    // In acked mode, relayed items are kept until the consumer acknowledges
+    // their relay sequence id.
+    acked: bool,
+    relay_session: String,
+    relay_seq: u64,
+    unacked: VecDeque<(u64, QueueItem)>,
+    // The relay ids of the replayed spool records, which stay on disk until
+    // they are acknowledged.
+    spooled_seqs: VecDeque<u64>,
+    relay_events: Option<BTreeSet<String>>,
+    sinks: Vec<SinkEntry>,
+}
+
+impl QueueManager {
+    // Keeps an item until the relay is up, on disk if we have a spool and in
+    // memory otherwise.
+    fn buffer_item(&mut self, item: QueueItem) {
+        if let Some(ref mut spool) = self.spool {
+            match spool.append(&serde_json::to_vec(&item).unwrap()) {
+                Ok(()) => {
+                    info!("Adding element to spool, size is now {}", spool.len());
+                    return;
+                }
+                Err(err) => error!("Failed to spool element: {}", err),
+            }
+        }
+
+        if self.queue.len() == self.max_queue_size {
+            // The queue is full, evict the oldest element.
+            info!("Queue overflow, removing element");
+            self.queue.pop_front();
+            STATS.queue_overflowed();
+        }
+        info!("Adding element to queue, size is now {}", self.queue.len());
+        self.queue.push_back(item);
+    }
+
+    // Hands an item to the relay writer if the relay is up. The item is given
+    // back if the relay is down, behind, waits for acks, or if it failed.
+    fn relay_item(&mut self, item: QueueItem) -> StdResult<(), QueueItem> {
+        let res = match self.relay {
+            // The consumer has to acknowledge records before we send more,
+            // so that unacknowledged records are never dropped.
+            Some(_) if self.acked && self.unacked.len() >= self.max_queue_size => {
+                debug!("Waiting for the relay to acknowledge records");
+                return Err(item);
+            }
+            Some(ref relay) => {
+                if self.acked {
+                    relay.send_record(self.relay_seq + 1, &item)
+                } else {
+                    relay.send(&item)
+                }
+            }
+            None => return Err(item),
+        };
+
+        match res {
+            Ok(()) => {}
+            Err(socket_relay::Error(socket_relay::ErrorKind::Busy, _)) => {
+                debug!("The relay consumer is behind, buffering");
+                self.retry_drain = Some(Instant::now() + RELAY_RETRY_DELAY);
+                return Err(item);
+            }
+            Err(err) => {
+                error!("Failed to relay item: {}", err);
+                self.on_relay_lost();
+                return Err(item);
+            }
+        }
+
+        if self.acked {
+            self.relay_seq += 1;
+            self.unacked.push_back((self.relay_seq, item));
+        }
+        Ok(())
+    }
+
+    fn has_buffered_items(&self) -> bool {
+        !self.queue.is_empty() || self.spool.as_ref().is_some_and(|spool| !spool.is_empty())
+    }
+
+    fn queue_item(&mut self, item: QueueItem) {
+        // Buffered items are older, so they are relayed first.
+        if self.has_buffered_items() {
+            self.buffer_item(item);
+            self.drain_queue();
+        } else if let Err(item) = self.relay_item(item) {
+            self.buffer_item(item);
+        }
+    }
+
+    // Hands an item to every sink whose events include it.
+    fn dispatch(&mut self, item: QueueItem) {
+        for entry in &mut self.sinks {
+            if accepts(&entry.events, &item) {
+                entry.sink.deliver(&item);
+            }
+        }
+        if accepts(&self.relay_events, &item) {
+            self.queue_item(item);
+        } else {
+            debug!("The relay doesn't take {} items", item.event_name());
+        }
+    }
+
+    // Switches back to buffering and starts trying to reconnect.
+    fn on_relay_lost(&mut self) {
+        if let Some(relay) = self.relay.take() {
+            info!("Lost the relay connection, buffering until it is back");
+            relay.close();
+            self.connect_relay();
+        }
+    }
+
+    // Starts connecting to the relay, giving up on the previous attempt.
+    fn connect_relay(&mut self) {
+        if let Some(connector) = self.connector.take() {
+            connector.cancel();
+        }
+        self.connector = Some(start_relay(
+            &self.config,
+            self.broker.clone(),
+            self.filter.clone(),
+        ));
+    }
+
+    fn on_relay_ready(&mut self, socket: SocketRelay) {
+        // Connections started before the relay address changed are obsolete.
+        if self.config.relay_address().ok().as_ref() != Some(socket.address()) {
+            debug!("Closing relay connection to an old address");
+            socket.close();
+            return;
+        }
+        if let Some(relay) = self.relay.take() {
+            relay.close();
+        }
+        // Connecting to a new address is not a reconnection.
+        if self.last_address.as_ref() == Some(socket.address()) {
+            STATS.relay_reconnected();
+        }
+        self.last_address = Some(socket.address().clone());
+
+        // Items that were not acknowledged on the previous connection are the
+        // oldest ones, so they are sent again first with their original id.
+        self.relay = Some(RelayWriter::start(
+            socket,
+            self.max_queue_size,
+            &self.relay_session,
+            &self.unacked,
+        ));
+        self.drain_queue();
+    }
+
+    // Buffers again the items a lost connection didn't write. In acked mode,
+    // they are still in the unacknowledged items.
+    fn on_relay_unsent(&mut self, lines: Vec<Vec<u8>>) {
+        if self.acked {
+            return;
+        }
+        info!("Buffering {} items the relay didn't write", lines.len());
+        for line in lines.iter().rev() {
+            match serde_json::from_slice::<QueueItem>(line) {
+                Ok(item) => self.queue.push_front(item),
+                Err(err) => error!("Dropping invalid unsent item: {}", err),
+            }
+        }
+        while self.queue.len() > self.max_queue_size {
+            self.queue.pop_front();
+            STATS.queue_overflowed();
+        }
+        self.drain_queue();
+    }
+
+    // Relays the buffered items, oldest first, until the relay is busy or gone.
+    fn drain_queue(&mut self) {
+        if self.relay.is_none() {
+            return;
+        }
+
+        // Replay the spool first since it holds the oldest buffered items.
+        // In acked mode, the replayed records are acknowledged in the spool
+        // along with their relay id, and invalid ones with the previous record.
+        if let Some(mut spool) = self.spool.take() {
+            debug!("Replaying {} spooled items", spool.len());
+            let mut delivered = 0;
+            let replayed = spool.replay(|data| {
+                let seq = match serde_json::from_slice::<QueueItem>(data) {
+                    Ok(item) => match self.relay_item(item) {
+                        Ok(()) => Some(self.relay_seq),
+                        Err(_) => return false,
+                    },
+                    Err(err) => {
+                        error!("Dropping invalid spooled item: {}", err);
+                        self.spooled_seqs.back().cloned()
+                    }
+                };
+                match seq {
+                    Some(seq) if self.acked => self.spooled_seqs.push_back(seq),
+                    _ => delivered += 1,
+                }
+                true
+            });
+            match replayed {
+                Ok(count) => debug!("Replayed {} spooled items", count),
+                Err(err) => error!("Failed to replay the spool: {}", err),
+            }
+            if let Err(err) = spool.ack(delivered) {
+                error!("Failed to remove replayed spool records: {}", err);
+            }
+            self.spool = Some(spool);
+        }
+
+        // Drain the queue.
+        debug!(
+            "About to drain {} items",
+            self.queue.len()
+        );
+        while let Some(item) = self.queue.pop_front() {
+            if let Err(item) = self.relay_item(item) {
+                self.queue.push_front(item);
+                break;
+            }
+        }
+    }
+
+    fn on_relay_ack(&mut self, seq: u64) {
+        while self.unacked.front().map(|item| item.0 <= seq) == Some(true) {
+            self.unacked.pop_front();
+        }
+        let mut delivered = 0;
+        while self.spooled_seqs.front().is_some_and(|spooled| *spooled <= seq) {
+            self.spooled_seqs.pop_front();
+            delivered += 1;
+        }
+        if let Some(ref mut spool) = self.spool {
+            if let Err(err) = spool.ack(delivered) {
+                error!("Failed to remove acknowledged spool records: {}", err);
+            }
+        }
+        debug!(
+            "Relay acknowledged up to {}, {} items left unacknowledged",
+            seq,
+            self.unacked.len()
+        );
+        self.drain_queue();
+    }
+
+    fn flush_aggregates(&mut self) {
+        for summary in self.aggregator.flush() {
+            debug!("Queueing counter summary of {}", summary.source);
+            self.dispatch(QueueItem::CounterSummary(Box::new(summary)));
+        }
+    }
+
+    fn flush_rollups(&mut self) {
+        for rollup in self.rollup.flush() {
+            debug!("Queueing rollup of cell {:?}", rollup.LI3);
+            self.dispatch(QueueItem::CellRollup(Box::new(rollup)));
+        }
+    }
+
+    // The end of the first window to close, or the next retry of a relay
+    // consumer that is behind.
+    fn next_timer(&self) -> Option<Instant> {
+        [
+            self.aggregator.next_flush(),
+            self.rollup.next_flush(),
+            self.retry_drain,
+        ]
+        .iter()
+        .filter_map(|deadline| *deadline)
+        .min()
+    }
+
+    fn on_timer(&mut self) {
+        let now = Instant::now();
+        if self.aggregator.next_flush().is_some_and(|d| d <= now) {
+            self.flush_aggregates();
+        }
+        if self.rollup.next_flush().is_some_and(|d| d <= now) {
+            self.flush_rollups();
+        }
+        if self.retry_drain.is_some_and(|d| d <= now) {
+            self.retry_drain = None;
+            self.drain_queue();
+        }
+    }
+
+    // Applies the new queue size, relay address and sink events. Other
+    // changes need a restart.
+    fn on_new_config(&mut self, config: Config) {
+        self.relay_events = sink_events(&config, "relay");
+        for entry in &mut self.sinks {
+            entry.events = sink_events(&config, entry.name);
+        }
+
+        if config.buffer_size != self.max_queue_size {
+            info!("Queue size is now {}", config.buffer_size);
+            self.max_queue_size = config.buffer_size;
+            while self.queue.len() > self.max_queue_size {
+                self.queue.pop_front();
+                STATS.queue_overflowed();
+            }
+        }
+
+        let address = config.relay_address().ok();
+        let address_changed = address != self.config.relay_address().ok();
+        self.config = config;
+        if address_changed {
+            if let Some(address) = address {
+                info!("Relaying to {}", address);
+            }
+            if let Some(relay) = self.relay.take() {
+                relay.close();
+            }
+            self.connect_relay();
+        }
+    }
+
+    // Relays what we can, and spools the rest for the next run.
+    fn shutdown(&mut self) {
+        info!("Shutting down queue manager thread");
+        let _ = self.broker.lock().unwrap().remove_actor("queue");
+        self.flush_aggregates();
+        self.flush_rollups();
+        self.drain_queue();
+
+        // Let the relay write what it was given, and buffer the rest.
+        if let Some(connector) = self.connector.take() {
+            connector.cancel();
+        }
+        if let Some(relay) = self.relay.take() {
+            let unsent = relay.shutdown();
+            if !unsent.is_empty() {
+                self.on_relay_unsent(unsent);
+            }
+        }
+
+        // Unacknowledged items are older than the queued ones. Those replayed
+        // from the spool are still in it.
+        if self.spool.is_some() {
+            let spooled = &self.spooled_seqs;
+            let items: Vec<QueueItem> = self
+                .unacked
+                .drain(..)
+                .filter(|&(seq, _)| !spooled.contains(&seq))
+                .map(|(_, item)| item)
+                .chain(self.queue.drain(..))
+                .collect();
+            for item in items {
+                self.buffer_item(item);
+            }
+        }
+        if !self.queue.is_empty() {
+            info!("Dropping {} queued items", self.queue.len());
+        }
+
+        for entry in self.sinks.drain(..) {
+            entry.sink.shutdown();
+        }
+    }
+
+    fn on_admin_request(&mut self, request: AdminRequest) -> admin::AdminResult {
+        match request {
+            AdminRequest::QueueStats => Ok(json!({
+                "queued": self.queue.len(),
+                "spooled": self.spool.as_ref().map_or(0, |spool| spool.len()),
+                "unacked": self.unacked.len(),
+                "relay_connected": self.relay.is_some(),
+                "sinks": self.sinks.iter().map(|entry| entry.name).collect::<Vec<_>>(),
+                "filter_stats": self.filter_stats,
+            })),
+            AdminRequest::FlushQueue => {
+                self.flush_aggregates();
+                self.flush_rollups();
+                self.drain_queue();
+                Ok(json!({ "queued": self.queue.len() }))
+            }
+            _ => Err(format!("Unexpected request: {:?}", request)),
+        }
+    }
+
+    fn run(&mut self, rx: Receiver<InternalMessage>) {
+        loop {
+            // Wake up at the end of the aggregation and rollup windows, and
+            // to retry a relay consumer that is behind.
+            let msg = match self.next_timer() {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    let timeout = if deadline > now {
+                        deadline - now
+                    } else {
+                        Default::default()
+                    };
+                    match rx.recv_timeout(timeout) {
+                        Ok(msg) => msg,
+                        Err(RecvTimeoutError::Timeout) => {
+                            self.on_timer();
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => panic!("Queue channel closed"),
+                    }
+                }
+                None => rx.recv().unwrap(),
+            };
+            match msg {
+                InternalMessage::NewClientMessage(source, payload) => {
+                    // Enforce the current filter, in case the client ignored it.
+                    let payload = if self.config.enforce_filter {
+                        let filter = self.filter.lock().unwrap().get();
+                        match payload.apply_filter(&filter, &mut self.filter_stats) {
+                            Some(payload) => payload,
+                            None => {
+                                debug!(
+                                    "Dropping filtered out payload, filter stats: {:?}",
+                                    self.filter_stats
+                                );
+                                continue;
+                            }
+                        }
+                    } else {
+                        payload
+                    };
+                    // Personal data never leaves this thread unscrubbed.
+                    let payload = match self.privacy.apply(payload) {
+                        Some(payload) => payload,
+                        None => {
+                            error!("Dropping payload that can't be scrubbed");
+                            continue;
+                        }
+                    };
+                    // Counters are relayed at the end of the window.
+                    let payload = match self.aggregator.add(&source, payload) {
+                        Some(payload) => payload,
+                        None => continue,
+                    };
+                    let payload = match self.rollup.add(payload) {
+                        Some(payload) => payload,
+                        None => continue,
+                    };
+                    debug!("Queueing payload");
+                    self.dispatch(QueueItem::ClientPayload(Box::new(payload)));
+                }
+                InternalMessage::RelayReady(socket) => self.on_relay_ready(socket),
+                InternalMessage::RelayAck(seq) => self.on_relay_ack(seq),
+                InternalMessage::RelayLost(id) => {
+                    if self.relay.as_ref().map(|relay| relay.id()) == Some(id) {
+                        self.on_relay_lost();
+                    }
+                }
+                InternalMessage::RelayUnsent(_, lines) => self.on_relay_unsent(lines),
+                InternalMessage::NewConfig(config) => self.on_new_config(*config),
+                InternalMessage::Shutdown => {
+                    self.shutdown();
+                    break;
+                }
+                InternalMessage::FilterAck(filter_ack) => {
+                    // Send the ack packet to the socket.
+                    // Since we send an initial filter packet, we can't be sure the relay is ready
+                    // when we get the ack, so we buffer here to if needed.
+                    debug!("Queueing filter ack");
+                    self.dispatch(QueueItem::FilterAck(filter_ack));
+                }
+                InternalMessage::Admin(request, reply) => {
+                    let result = self.on_admin_request(request);
+                    admin::reply(&reply, result);
+                }
+                InternalMessage::NewFilter(_) => {
+                    // Nothing to do here.
+                }
+            }
         }
     }
 }
 
+/// Starts the queue manager thread, which exits once it relayed or spooled
+/// what it could after getting the Shutdown message.
 pub fn start_queue_manager(
     config: &Config,
     broker: SharedMessageBroker<InternalMessage>,
     filter: SharedFilterFrame,
-) {
+) -> JoinHandle<()> {
     let (tx, rx) = channel::<InternalMessage>();
     {
         let mut guard = broker.lock().unwrap();
         guard.add_actor("queue", tx.clone()).unwrap();
     }
-    let max_queue_size = config.buffer_size;
-    start_relay(&config, broker.clone(), filter.clone());
+
+    let mut manager = QueueManager {
+        config: config.clone(),
+        broker: broker.clone(),
+        filter: filter.clone(),
+        max_queue_size: config.buffer_size,
+        queue: VecDeque::new(),
+        // Events spooled by a previous run are replayed once the relay is up.
+        spool: open_spool(config),
+        relay: None,
+        connector: None,
+        last_address: None,
+        retry_drain: None,
+        filter_stats: FilterStats::default(),
+        privacy: Privacy::new(config),
+        aggregator: Aggregator::new(config.aggregation_window),
+        rollup: Rollup::new(config.rollup_window),
+        acked: config.relay_acks,
+        relay_session: new_relay_session(),
+        relay_seq: 0,
+        unacked: VecDeque::new(),
+        spooled_seqs: VecDeque::new(),
+        relay_events: sink_events(config, "relay"),
+        // The other sinks keep their own buffer, so they get their items
+        // whatever the state of the relay is.
+        sinks: open_sinks(config),
+    };
+
+    manager.connect_relay();
 
     thread::Builder::new()
         .name("queue manager".to_owned())
-        .spawn(move || {
-            let mut queue = VecDeque::<QueueItem>::new();
-            let mut relay: Option<SocketRelay> = None;
-
-            loop {
-                let msg = rx.recv().unwrap();
-                match msg {
-                    InternalMessage::NewClientMessage(payload) => {
-                        if let Some(ref mut relay) = relay {
-                            debug!("Queue relay socket is ready, sending payload");
-                            relay.send(&payload).expect("Failed to relay payload");
-                        } else {
-                            // Add to queue if the relay is not up.
-
-                            if queue.len() == max_queue_size {
-                                // The queue is full, evict the oldest element.
-                                info!("Queue overflow, removing element");
-                                queue.pop_front();
-                            }
-                            info!("Adding element to queue, size is now {}", queue.len());
-                            queue.push_back(QueueItem::ClientPayload(payload));
-                        }
-                    }
-                    InternalMessage::RelayReady(mut socket) => {
-                        // Drain the queue.
-                        debug!(
-                            "Queue relay socket is ready, about to drain {} items",
-                            queue.len()
-                        );
-                        for item in queue.drain(..) {
-                            socket.send(&item).expect("Failed to relay drained payload");
-                        }
+        .spawn(move || manager.run(rx))
+        .expect("Failed to create queue manager thread")
+}
 
-                        relay = Some(socket);
-                    }
-                    InternalMessage::Shutdown => {
-                        info!("Shutting down queue manager thread");
-                        break;
-                    }
-                    InternalMessage::FilterAck(filter_ack) => {
-                        // Send the ack packet to the socket.
-                        // Since we send an initial filter packet, we can't be sure the relay is ready
-                        // when we get the ack, so we buffer here to if needed.
-                        if let Some(ref mut relay) = relay {
-                            debug!("Queue relay socket is ready, sending filter ack");
-                            relay.send(&filter_ack).expect("Failed to relay filter ack");
-                        } else {
-                            // Add to queue if the relay is not up.
-
-                            if queue.len() == max_queue_size {
-                                // The queue is full, evict the oldest element.
-                                info!("Queue overflow, removing element");
-                                queue.pop_front();
-                            }
-                            info!("Adding element to queue, size is now {}", queue.len());
-                            queue.push_back(QueueItem::FilterAck(filter_ack));
-                        }
-                    }
-                    InternalMessage::NewFilter(_) => {
-                        // Nothing to do here.
-                    }
-                }
-            }
-        }).expect("Failed to create queue manager thread");
+// Queues payloads with these names, as a client would.
+#[cfg(test)]
+fn send_test_payloads(broker: &SharedMessageBroker<InternalMessage>, names: &[&str]) {
+    for name in names {
+        let mut payload = ClientPayload::default();
+        payload.name = name.to_string();
+        payload.DT = Some("now".to_owned());
+        broker
+            .lock()
+            .unwrap()
+            .send_message("queue", InternalMessage::NewClientMessage("test".into(), payload))
+            .unwrap();
+    }
+}
+
+// Starts a queue manager relaying to a new local listener, and sends it the
+// `names` payloads. The relay_port of `config` is set to the listener's.
+#[cfg(test)]
+fn start_test_queue(
+    config: &mut Config,
+    names: &[&str],
+) -> (
+    ::std::net::TcpListener,
+    SharedMessageBroker<InternalMessage>,
+    JoinHandle<()>,
+) {
+    use frame_messages::default_shared_filterframe;
+    use message_broker::MessageBroker;
+
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    config.relay_port = listener.local_addr().unwrap().port();
+    let broker = MessageBroker::new_shared();
+    let thread = start_queue_manager(config, broker.clone(), default_shared_filterframe());
+    send_test_payloads(&broker, names);
+    (listener, broker, thread)
+}
+
+#[test]
+fn test_spool_replay_on_relay_ready() {
+    use frame_messages::default_shared_filterframe;
+    use message_broker::MessageBroker;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    let dir = ::std::env::temp_dir().join(format!("metrics_queue_spool_{}", ::std::process::id()));
+    let _ = ::std::fs::remove_dir_all(&dir);
+
+    // Leave an item from a "previous run" in the spool.
+    {
+        let mut spool = Spool::open(&dir, 1024 * 1024, 100, 1024).unwrap();
+        let mut payload = ClientPayload::default();
+        payload.name = "NE1".to_owned();
+        spool
+            .append(&serde_json::to_vec(&QueueItem::ClientPayload(Box::new(payload))).unwrap())
+            .unwrap();
+    }
+
+    // Find a free port for the relay, that nobody listens to yet.
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config = Config {
+        relay_port: port,
+        spool_path: Some(dir.to_str().unwrap().to_owned()),
+        ..Config::default()
+    };
+
+    let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
+    start_queue_manager(&config, broker.clone(), default_shared_filterframe());
+
+    send_test_payloads(&broker, &["NE2", "NE3"]);
+    broker
+        .lock()
+        .unwrap()
+        .send_message("queue", InternalMessage::FilterAck(FilterAck::default()))
+        .unwrap();
+
+    // Let the relay fail to connect before it comes up.
+    thread::sleep(Duration::from_millis(500));
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+    let relay = listener.incoming().next().unwrap().unwrap();
+    let lines: Vec<String> = BufReader::new(relay)
+        .lines()
+        .take(4)
+        .map(|line| line.unwrap().trim().to_owned())
+        .collect();
+    assert_eq!(
+        lines,
+        vec![
+            r#"{"Name":"NE1","DT":null}"#,
+            r#"{"Name":"NE2","DT":"now"}"#,
+            r#"{"Name":"NE3","DT":"now"}"#,
+            r#"{"kind":"FilterAck","success":true}"#,
+        ]
+    );
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+    let _ = ::std::fs::remove_dir_all(&dir);
+}
+
+// Splits the relay session id off a record line.
+#[cfg(test)]
+fn split_session(line: &str) -> (String, String) {
+    let prefix = r#"{"relay_session":""#;
+    assert!(line.starts_with(prefix), "No relay session in {}", line);
+    let (session, rest) = line[prefix.len()..].split_at(16);
+    (session.to_owned(), format!("{{{}", &rest[2..]))
+}
+
+#[test]
+fn test_acked_relay() {
+    use frame_messages::default_shared_filterframe;
+    use std::io::{BufRead, BufReader, Write};
+    use std::time::Duration;
+
+    let mut config = Config {
+        relay_acks: true,
+        ..Config::default()
+    };
+    let (listener, broker, _) = start_test_queue(&mut config, &["NE1", "NE2"]);
+    let mut relay = listener.incoming().next().unwrap().unwrap();
+
+    let (sessions, lines): (Vec<String>, Vec<String>) = BufReader::new(relay.try_clone().unwrap())
+        .lines()
+        .take(2)
+        .map(|line| split_session(line.unwrap().trim()))
+        .unzip();
+    let session = sessions[0].clone();
+    assert_eq!(sessions, vec![session.clone(), session.clone()]);
+    assert_eq!(
+        lines,
+        vec![
+            r#"{"relay_seq":1,"record":{"Name":"NE1","DT":"now"}}"#,
+            r#"{"relay_seq":2,"record":{"Name":"NE2","DT":"now"}}"#,
+        ]
+    );
+
+    // Only acknowledge the first record, then simulate a reconnection.
+    relay.write_all(b"{\"ack\":1}\n").unwrap();
+    thread::sleep(Duration::from_millis(200));
+    start_relay(&config, broker.clone(), default_shared_filterframe());
+    let relay = listener.incoming().next().unwrap().unwrap();
+    send_test_payloads(&broker, &["NE3"]);
+
+    // The resent record keeps its session and id.
+    let (sessions, lines): (Vec<String>, Vec<String>) = BufReader::new(relay)
+        .lines()
+        .take(2)
+        .map(|line| split_session(line.unwrap().trim()))
+        .unzip();
+    assert_eq!(sessions, vec![session.clone(), session]);
+    assert_eq!(
+        lines,
+        vec![
+            r#"{"relay_seq":2,"record":{"Name":"NE2","DT":"now"}}"#,
+            r#"{"relay_seq":3,"record":{"Name":"NE3","DT":"now"}}"#,
+        ]
+    );
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+}
+
+#[test]
+fn test_acked_window() {
+    use std::io::{BufRead, BufReader, Write};
+
+    let dir = ::std::env::temp_dir().join(format!("metrics_queue_window_{}", ::std::process::id()));
+    let _ = ::std::fs::remove_dir_all(&dir);
+    let mut config = Config {
+        relay_acks: true,
+        buffer_size: 2,
+        spool_path: Some(dir.to_str().unwrap().to_owned()),
+        ..Config::default()
+    };
+    let names = ["NE1", "NE2", "NE3", "NE4", "NE5"];
+    let (listener, broker, _) = start_test_queue(&mut config, &names);
+    let mut relay = listener.incoming().next().unwrap().unwrap();
+
+    // Only buffer_size records are in flight, the others wait for acks.
+    let mut lines = BufReader::new(relay.try_clone().unwrap()).lines();
+    let mut read = |count: usize| -> Vec<String> {
+        (0..count)
+            .map(|_| split_session(lines.next().unwrap().unwrap().trim()).1)
+            .collect()
+    };
+    assert_eq!(
+        read(2),
+        vec![
+            r#"{"relay_seq":1,"record":{"Name":"NE1","DT":"now"}}"#,
+            r#"{"relay_seq":2,"record":{"Name":"NE2","DT":"now"}}"#,
+        ]
+    );
+    relay.write_all(b"{\"ack\":2}\n").unwrap();
+    assert_eq!(
+        read(2),
+        vec![
+            r#"{"relay_seq":3,"record":{"Name":"NE3","DT":"now"}}"#,
+            r#"{"relay_seq":4,"record":{"Name":"NE4","DT":"now"}}"#,
+        ]
+    );
+    relay.write_all(b"{\"ack\":4}\n").unwrap();
+    assert_eq!(
+        read(1),
+        vec![r#"{"relay_seq":5,"record":{"Name":"NE5","DT":"now"}}"#]
+    );
+
+    // The replayed records stay in the spool until they are acknowledged.
+    let segments = || ::std::fs::read_dir(&dir).unwrap().count();
+    assert_eq!(segments(), 1);
+    relay.write_all(b"{\"ack\":5}\n").unwrap();
+    for _ in 0..50 {
+        if segments() == 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(segments(), 0);
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+    let _ = ::std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_relay_reconnection() {
+    use std::io::{BufRead, BufReader};
+    use std::net::Shutdown;
+    use std::time::Duration;
+
+    let (listener, broker, _) = start_test_queue(&mut Config::default(), &[]);
+
+    // The consumer gets a first payload, and then goes away.
+    {
+        let relay = listener.incoming().next().unwrap().unwrap();
+        send_test_payloads(&broker, &["NE1"]);
+        let line = BufReader::new(relay.try_clone().unwrap())
+            .lines()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(line.trim(), r#"{"Name":"NE1","DT":"now"}"#);
+        relay.shutdown(Shutdown::Both).unwrap();
+    }
+
+    // Payloads are buffered until the consumer comes back.
+    thread::sleep(Duration::from_millis(200));
+    send_test_payloads(&broker, &["NE2", "NE3"]);
+
+    let relay = listener.incoming().next().unwrap().unwrap();
+    let lines: Vec<String> = BufReader::new(relay)
+        .lines()
+        .take(2)
+        .map(|line| line.unwrap().trim().to_owned())
+        .collect();
+    assert_eq!(
+        lines,
+        vec![
+            r#"{"Name":"NE2","DT":"now"}"#,
+            r#"{"Name":"NE3","DT":"now"}"#,
+        ]
+    );
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+}
+
+#[test]
+fn test_config_reload() {
+    use frame_messages::default_shared_filterframe;
+    use message_broker::MessageBroker;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    // Nobody listens on the first port.
+    let old_port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config = Config {
+        relay_port: old_port,
+        buffer_size: 5,
+        ..Config::default()
+    };
+
+    let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
+    start_queue_manager(&config, broker.clone(), default_shared_filterframe());
+
+    send_test_payloads(&broker, &["NE1", "NE2", "NE3", "NE4"]);
+
+    // The queue shrinks to the newest items, which go to the new port.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let new_config = Config {
+        relay_port: listener.local_addr().unwrap().port(),
+        buffer_size: 2,
+        ..config
+    };
+    broker
+        .lock()
+        .unwrap()
+        .send_message("queue", InternalMessage::NewConfig(Box::new(new_config)))
+        .unwrap();
+
+    let relay = listener.incoming().next().unwrap().unwrap();
+    let lines: Vec<String> = BufReader::new(relay)
+        .lines()
+        .take(2)
+        .map(|line| line.unwrap().trim().to_owned())
+        .collect();
+    assert_eq!(
+        lines,
+        vec![
+            r#"{"Name":"NE3","DT":"now"}"#,
+            r#"{"Name":"NE4","DT":"now"}"#,
+        ]
+    );
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+}
+
+#[test]
+fn test_no_raw_identifiers_relayed() {
+    use std::io::{BufRead, BufReader};
+
+    let mut config = Config {
+        privacy: serde_json::from_value(json!({
+            "DI1": { "policy": "hash" },
+            "DI2": { "policy": "drop" },
+            "DI3": { "policy": "truncate", "length": 4 },
+            "LI5": { "policy": "grid", "size": 0.1 },
+            "LI6": { "policy": "grid", "size": 0.1 }
+        }))
+        .unwrap(),
+        ..Config::default()
+    };
+    let (listener, broker, _) = start_test_queue(&mut config, &[]);
+    let relay = listener.incoming().next().unwrap().unwrap();
+
+    let raw = [
+        "356938035643809",
+        "404450123456789",
+        "+919876543210",
+        "22.5726",
+        "88.3639",
+    ];
+    for name in &["NE1", "NE2", "NE3"] {
+        let payload: ClientPayload = serde_json::from_value(json!({
+            "Name": name,
+            "DT": "now",
+            "DI1": raw[0],
+            "DI2": raw[1],
+            "DI3": raw[2],
+            "LI5": 22.5726,
+            "LI6": 88.3639
+        }))
+        .unwrap();
+        broker
+            .lock()
+            .unwrap()
+            .send_message("queue", InternalMessage::NewClientMessage("test".into(), payload))
+            .unwrap();
+    }
+
+    let lines: Vec<String> = BufReader::new(relay)
+        .lines()
+        .take(3)
+        .map(|line| line.unwrap())
+        .collect();
+    assert_eq!(lines.len(), 3);
+    for line in lines {
+        for value in raw.iter() {
+            assert!(!line.contains(value), "{} leaked in {}", value, line);
+        }
+        let fields: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!(fields["DI1"].as_str().unwrap().len(), 64);
+        assert_eq!(fields["DI3"], json!("+919"));
+    }
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+}
+
+#[test]
+fn test_counter_aggregation() {
+    use std::io::{BufRead, BufReader};
+
+    let mut config = Config {
+        aggregation_window: 1,
+        ..Config::default()
+    };
+    let (listener, broker, _) = start_test_queue(&mut config, &[]);
+    let relay = listener.incoming().next().unwrap().unwrap();
+
+    let send_payload = |value: serde_json::Value| {
+        let payload: ClientPayload = serde_json::from_value(value).unwrap();
+        broker
+            .lock()
+            .unwrap()
+            .send_message("queue", InternalMessage::NewClientMessage("ril".into(), payload))
+            .unwrap();
+    };
+    send_payload(json!({ "Name": "NE11", "DT": "now", "NC1": 1, "HI1": 10 }));
+    send_payload(json!({ "Name": "NE11", "DT": "now", "NC1": 2, "HI1": 20 }));
+    send_payload(json!({ "Name": "NE5", "DT": "now", "NE5": "drop" }));
+
+    // The event is relayed right away, and the counters at the end of the window.
+    let mut lines = BufReader::new(relay).lines();
+    let line = lines.next().unwrap().unwrap();
+    assert_eq!(line.trim(), r#"{"Name":"NE5","DT":"now","NE5":"drop"}"#);
+    let line = lines.next().unwrap().unwrap();
+    let summary: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+    assert_eq!(summary["kind"], json!("CounterSummary"));
+    assert_eq!(summary["source"], json!("ril"));
+    assert_eq!(summary["payloads"], json!(2));
+    assert_eq!(summary["NC1"], json!(3));
+    assert_eq!(summary["HI1"], json!(30));
+
+    // Spooled summaries are read back as such.
+    match serde_json::from_str::<QueueItem>(line.trim()).unwrap() {
+        QueueItem::CounterSummary(ref val) => assert_eq!(val.totals["NC1"], 3),
+        _ => panic!("Not a counter summary"),
+    }
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+}
+
+#[test]
+fn test_cell_rollups() {
+    use std::io::{BufRead, BufReader};
+
+    let mut config = Config {
+        rollup_window: 1,
+        ..Config::default()
+    };
+    let (listener, broker, _) = start_test_queue(&mut config, &[]);
+    let relay = listener.incoming().next().unwrap().unwrap();
+
+    for rsrq in &[10, 20, 30] {
+        let payload: ClientPayload = serde_json::from_value(json!({
+            "Name": "NE11", "DT": "now", "LI3": 56789, "RI2": rsrq
+        }))
+        .unwrap();
+        broker
+            .lock()
+            .unwrap()
+            .send_message("queue", InternalMessage::NewClientMessage("ril".into(), payload))
+            .unwrap();
+    }
+
+    // Only the rollup is relayed, at the end of the window.
+    let line = BufReader::new(relay).lines().next().unwrap().unwrap();
+    let rollup: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+    assert_eq!(rollup["kind"], json!("CellRollup"));
+    assert_eq!(rollup["LI3"], json!(56789));
+    assert_eq!(rollup["samples"], json!(3));
+    assert_eq!(rollup["RI2"]["mean"], json!(20.0));
+    assert_eq!(rollup["RI2"]["max"], json!(30));
+
+    // Spooled rollups are read back as such.
+    match serde_json::from_str::<QueueItem>(line.trim()).unwrap() {
+        QueueItem::CellRollup(ref val) => assert_eq!(val.samples, 3),
+        _ => panic!("Not a cell rollup"),
+    }
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+}
+
+#[test]
+fn test_sink_fan_out() {
+    use std::fs;
+    use std::io::{BufRead, BufReader};
+
+    let path =
+        ::std::env::temp_dir().join(format!("metrics_file_sink_{}.log", ::std::process::id()));
+    let _ = fs::remove_file(&path);
+    let mut config = Config {
+        file_sink_path: Some(path.to_str().unwrap().to_owned()),
+        ..Config::default()
+    };
+    config.sink_events.insert("relay".into(), vec!["NE1".into()]);
+    config
+        .sink_events
+        .insert("file".into(), vec!["NE2".into(), "FilterAck".into()]);
+
+    let (listener, broker, thread) = start_test_queue(&mut config, &["NE2", "NE1"]);
+    let relay = listener.incoming().next().unwrap().unwrap();
+    broker
+        .lock()
+        .unwrap()
+        .send_message("queue", InternalMessage::FilterAck(FilterAck::default()))
+        .unwrap();
+
+    // Each sink only gets its events.
+    let line = BufReader::new(relay).lines().next().unwrap().unwrap();
+    assert_eq!(line.trim(), r#"{"Name":"NE1","DT":"now"}"#);
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+    thread.join().unwrap();
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "{\"Name\":\"NE2\",\"DT\":\"now\"}\n{\"kind\":\"FilterAck\",\"success\":true}\n"
+    );
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn test_stalled_relay() {
+    use std::fs;
+
+    let path =
+        ::std::env::temp_dir().join(format!("metrics_stalled_relay_{}.log", ::std::process::id()));
+    let _ = fs::remove_file(&path);
+    let mut config = Config {
+        buffer_size: 2,
+        file_sink_path: Some(path.to_str().unwrap().to_owned()),
+        ..Config::default()
+    };
+    // Only the relay gets the large payloads.
+    config.sink_events.insert("file".into(), vec!["NE2".into()]);
+
+    let (listener, broker, thread) = start_test_queue(&mut config, &[]);
+    // The consumer never reads, so the socket buffers fill up.
+    let relay = listener.incoming().next().unwrap().unwrap();
+
+    let send_payload = |name: &str, date: String| {
+        let mut payload = ClientPayload::default();
+        payload.name = name.to_owned();
+        payload.DT = Some(date);
+        broker
+            .lock()
+            .unwrap()
+            .send_message("queue", InternalMessage::NewClientMessage("test".into(), payload))
+            .unwrap();
+    };
+    for _ in 0..200 {
+        send_payload("NE1", "x".repeat(100 * 1024));
+    }
+    send_payload("NE2", "now".into());
+
+    // The file sink gets its items well before the relay write times out.
+    let start = Instant::now();
+    while !fs::read_to_string(&path)
+        .unwrap_or_default()
+        .contains(r#"{"Name":"NE2","DT":"now"}"#)
+    {
+        assert!(start.elapsed() < Duration::from_secs(2), "The file sink is stalled");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    // Shutdown doesn't wait for the relay write to time out.
+    let start = Instant::now();
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+    thread.join().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(3));
+    drop(relay);
+    let _ = fs::remove_file(&path);
 }
@@ -0,0 +1,277 @@
+// (c) 2017 KAI OS TECHNOLOGIES (HONG KONG) LIMITED All rights reserved. This
+// file or any portion thereof may not be reproduced or used in any manner
+// whatsoever without the express written permission of KAI OS TECHNOLOGIES
+// (HONG KONG) LIMITED. KaiOS is the trademark of KAI OS TECHNOLOGIES (HONG KONG)
+// LIMITED or its affiliate company and may be registered in some jurisdictions.
+// All other trademarks are the property of their respective owners.
+
+/// Rolls up the radio KPIs (RI1 to RI4) of each serving cell, to relay their
+/// statistics once per window instead of every sample.
+///
+/// The KPIs are small integers, so exact histograms are cheap enough and
+/// give exact percentiles.
+use chrono::{Timelike, Utc};
+use frame_messages::ClientPayload;
+use serde_json::{self, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+const KPIS: [&str; 4] = ["RI1", "RI2", "RI3", "RI4"];
+const CELL_IDS: [&str; 4] = ["LI1", "LI2", "LI3", "LI4"];
+
+/// The statistics of a KPI over a window.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct KpiStats {
+    pub count: u64,
+    pub min: i64,
+    pub max: i64,
+    pub mean: f64,
+    pub p50: i64,
+    pub p90: i64,
+}
+
+/// The KPIs of a cell over a window.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[allow(non_snake_case)]
+pub struct CellRollup {
+    pub kind: String,  // Always "CellRollup".
+    pub start: String, // Window start, in the DT format.
+    pub end: String,   // Window end, in the DT format.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub LI1: Option<u64>, // MCC/MNC
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub LI2: Option<u64>, // Tracking Area Code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub LI3: Option<u64>, // Global Cell identifier
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub LI4: Option<u64>, // Physical cell identifier
+    pub samples: u64,  // The number of payloads with KPIs.
+    #[serde(flatten)]
+    pub kpis: BTreeMap<String, KpiStats>,
+}
+
+// Value -> number of samples.
+type Histogram = BTreeMap<i64, u64>;
+
+// Nearest-rank percentile.
+fn percentile(histogram: &Histogram, count: u64, percent: u64) -> i64 {
+    let rank = ((count * percent + 99) / 100).max(1);
+    let mut seen = 0;
+    for (&value, &samples) in histogram {
+        seen += samples;
+        if seen >= rank {
+            return value;
+        }
+    }
+    0
+}
+
+fn stats(histogram: &Histogram) -> KpiStats {
+    let count: u64 = histogram.values().sum();
+    let sum: i64 = histogram
+        .iter()
+        .map(|(&value, &samples)| value * samples as i64)
+        .sum();
+    KpiStats {
+        count,
+        min: *histogram.keys().next().unwrap_or(&0),
+        max: *histogram.keys().next_back().unwrap_or(&0),
+        mean: sum as f64 / count as f64,
+        p50: percentile(histogram, count, 50),
+        p90: percentile(histogram, count, 90),
+    }
+}
+
+fn now() -> String {
+    // We don't want sub-second precision
+    format!("{:?}", Utc::now().with_nanosecond(0).unwrap())
+}
+
+type CellKey = [Option<u64>; 4];
+
+#[derive(Default)]
+struct Cell {
+    samples: u64,
+    histograms: BTreeMap<String, Histogram>,
+}
+
+pub struct Rollup {
+    window: Option<Duration>, // None when rollups are disabled.
+    window_start: String,
+    next_flush: Instant,
+    cells: HashMap<CellKey, Cell>,
+}
+
+impl Rollup {
+    /// A window of 0 seconds disables rollups.
+    pub fn new(window: u64) -> Self {
+        let window = if window == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(window))
+        };
+        Rollup {
+            window,
+            window_start: now(),
+            next_flush: Instant::now() + window.unwrap_or_default(),
+            cells: HashMap::new(),
+        }
+    }
+
+    /// When the current window ends, if rollups are enabled.
+    pub fn next_flush(&self) -> Option<Instant> {
+        self.window.map(|_| self.next_flush)
+    }
+
+    fn cell_key(fields: &Map<String, Value>) -> Option<CellKey> {
+        let mut key = [None; 4];
+        for (id, field) in key.iter_mut().zip(CELL_IDS.iter()) {
+            *id = fields.get(*field).and_then(|value| value.as_u64());
+        }
+        if key.iter().all(|id| id.is_none()) {
+            None
+        } else {
+            Some(key)
+        }
+    }
+
+    /// Takes the KPIs out of payloads with cell identifiers, and returns what's
+    /// left of the payload if it still carries other data.
+    pub fn add(&mut self, payload: ClientPayload) -> Option<ClientPayload> {
+        if self.window.is_none() {
+            return Some(payload);
+        }
+
+        let mut fields = match serde_json::to_value(&payload) {
+            Ok(Value::Object(fields)) => fields,
+            _ => return Some(payload),
+        };
+        let key = match Rollup::cell_key(&fields) {
+            Some(key) => key,
+            None => return Some(payload),
+        };
+        let kpis: Vec<(String, i64)> = KPIS
+            .iter()
+            .filter_map(|kpi| {
+                fields
+                    .get(*kpi)
+                    .and_then(|value| value.as_i64())
+                    .map(|value| (kpi.to_string(), value))
+            })
+            .collect();
+        if kpis.is_empty() {
+            return Some(payload);
+        }
+
+        let cell = self.cells.entry(key).or_default();
+        cell.samples += 1;
+        for (kpi, value) in kpis {
+            fields.remove(&kpi);
+            *cell
+                .histograms
+                .entry(kpi)
+                .or_default()
+                .entry(value)
+                .or_insert(0) += 1;
+        }
+
+        // The cell identifiers alone are not worth relaying.
+        let has_data = fields.iter().any(|(field, value)| {
+            let field = field.as_str();
+            field != "Name" && field != "DT" && !CELL_IDS.contains(&field) && !value.is_null()
+        });
+        if has_data {
+            serde_json::from_value(Value::Object(fields)).ok()
+        } else {
+            None
+        }
+    }
+
+    /// Ends the current window, returning the rollup of each cell.
+    pub fn flush(&mut self) -> Vec<CellRollup> {
+        let end = now();
+        let start = ::std::mem::replace(&mut self.window_start, end.clone());
+        if let Some(window) = self.window {
+            self.next_flush = Instant::now() + window;
+        }
+
+        let mut rollups: Vec<CellRollup> = self
+            .cells
+            .drain()
+            .map(|(key, cell)| CellRollup {
+                kind: "CellRollup".into(),
+                start: start.clone(),
+                end: end.clone(),
+                LI1: key[0],
+                LI2: key[1],
+                LI3: key[2],
+                LI4: key[3],
+                samples: cell.samples,
+                kpis: cell
+                    .histograms
+                    .iter()
+                    .map(|(kpi, histogram)| (kpi.clone(), stats(histogram)))
+                    .collect(),
+            })
+            .collect();
+        rollups.sort_by_key(|rollup| [rollup.LI1, rollup.LI2, rollup.LI3, rollup.LI4]);
+        rollups
+    }
+}
+
+#[test]
+fn rollup_kpis() {
+    let mut rollup = Rollup::new(60);
+    let payload = |value: Value| -> ClientPayload { serde_json::from_value(value).unwrap() };
+
+    // Samples are absorbed.
+    for rsrp in 1..11 {
+        let res = rollup.add(payload(json!({
+            "Name": "NE11", "LI1": 46001, "LI3": 56789, "RI1": rsrp, "RI3": -5
+        })));
+        assert!(res.is_none());
+    }
+    let res = rollup.add(payload(
+        json!({ "Name": "NE11", "LI1": 46001, "LI3": 1, "RI1": 30 }),
+    ));
+    assert!(res.is_none());
+
+    // Events keep their cell, but lose their KPIs.
+    let res = rollup.add(payload(json!({
+        "Name": "NE19", "NE19": "rlf", "LI1": 46001, "LI3": 1, "RI1": 40
+    })));
+    let fields = serde_json::to_value(res.unwrap()).unwrap();
+    assert_eq!(fields["NE19"], json!("rlf"));
+    assert_eq!(fields["LI3"], json!(1));
+    assert!(fields.get("RI1").is_none());
+
+    // Payloads without cell identifiers go through.
+    let res = rollup.add(payload(json!({ "Name": "NE11", "RI1": 40 })));
+    assert!(res.is_some());
+
+    let rollups = rollup.flush();
+    assert_eq!(rollups.len(), 2);
+    assert_eq!(rollups[0].LI3, Some(1));
+    assert_eq!(rollups[0].samples, 2);
+    assert_eq!(rollups[0].kpis["RI1"].mean, 35.0);
+
+    let cell = &rollups[1];
+    assert_eq!(cell.LI3, Some(56789));
+    assert_eq!(cell.LI2, None);
+    assert_eq!(cell.samples, 10);
+    assert_eq!(
+        cell.kpis["RI1"],
+        KpiStats {
+            count: 10,
+            min: 1,
+            max: 10,
+            mean: 5.5,
+            p50: 5,
+            p90: 9,
+        }
+    );
+    assert_eq!(cell.kpis["RI3"].min, -5);
+    assert!(!cell.kpis.contains_key("RI2"));
+
+    assert!(rollup.flush().is_empty());
+}
@@ -5,18 +5,29 @@
 // LIMITED or its affiliate company and may be registered in some jurisdictions.
 // All other trademarks are the property of their respective owners.
 
-/// Socket relay: this allows sending frames on a TCP Socket.
-use config::Config;
+/// Socket relay: this allows sending frames on a TCP or Unix socket.
+use config::{Config, RelayAddress};
 use frame_messages::{FilterFrame, SharedFilterFrame};
 use internal_messages::InternalMessage;
+use libc;
 use message_broker::SharedMessageBroker;
 use serde::Serialize;
 use serde_json;
+use stats::STATS;
 use std::fmt;
-use std::io::{BufReader, Read, Write};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
 use std::net::TcpStream;
+use std::net::Shutdown;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
 use std::result::Result as StdResult;
-use std::{thread, time};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::{cmp, thread, time};
 
 error_chain!{
     errors {
@@ -24,6 +35,16 @@ error_chain!{
             description("The socket is not ready yet")
             display("The socket is not ready yet")
         }
+
+        Busy {
+            description("The relay consumer is busy")
+            display("The relay consumer is busy")
+        }
+
+        Closed {
+            description("The relay connection is closed")
+            display("The relay connection is closed")
+        }
     }
 
     foreign_links {
@@ -31,8 +52,154 @@ error_chain!{
     }
 }
 
+// The envelope of records sent in acked mode.
+#[derive(Serialize)]
+struct RelayRecord<'a, T: 'a + Serialize> {
+    relay_session: &'a str,
+    relay_seq: u64,
+    record: &'a T,
+}
+
+// The client expects a JSON string with a \n ending, not a frame.
+fn relay_line<T: Serialize>(payload: &T) -> Vec<u8> {
+    let mut v = serde_json::to_vec(payload).unwrap();
+    v.push(b'\n');
+    v.push(b' ');
+    v
+}
+
+// Acknowledges every record up to and including `ack`.
+#[derive(Deserialize)]
+struct RelayAck {
+    ack: u64,
+}
+
+// A consumer that doesn't read for that long is considered gone, so that
+// the queue and the other sinks don't wait for it.
+const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+// How long the queued lines may take to be written on shutdown, well within
+// the default shutdown_timeout. The lines that are left are spooled.
+const SHUTDOWN_WRITE_DEADLINE: time::Duration = time::Duration::from_secs(1);
+
+// The uid of the process at the other end of a Unix socket.
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+// The connection to the consumer.
+#[derive(Debug)]
+enum RelayStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl RelayStream {
+    // Connects to the consumer. On a Unix socket, it has to run as `uid`,
+    // since another process could have bound the path first.
+    fn connect(address: &RelayAddress, uid: u32) -> io::Result<Self> {
+        match *address {
+            RelayAddress::Tcp(ref address) => Ok(RelayStream::Tcp(TcpStream::connect(
+                address.as_str(),
+            )?)),
+            RelayAddress::Unix(ref path) => {
+                let stream = UnixStream::connect(path)?;
+                let peer = peer_uid(&stream)?;
+                if peer != uid {
+                    error!(
+                        "Refusing the relay consumer at {}, its uid is {} instead of {}",
+                        path.display(),
+                        peer,
+                        uid
+                    );
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return Err(io::ErrorKind::PermissionDenied.into());
+                }
+                Ok(RelayStream::Unix(stream))
+            }
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        match *self {
+            RelayStream::Tcp(ref stream) => stream.try_clone().map(RelayStream::Tcp),
+            RelayStream::Unix(ref stream) => stream.try_clone().map(RelayStream::Unix),
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        match *self {
+            RelayStream::Tcp(ref stream) => stream.shutdown(Shutdown::Both),
+            RelayStream::Unix(ref stream) => stream.shutdown(Shutdown::Both),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        match *self {
+            RelayStream::Tcp(ref stream) => stream.set_read_timeout(timeout),
+            RelayStream::Unix(ref stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_write_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        match *self {
+            RelayStream::Tcp(ref stream) => stream.set_write_timeout(timeout),
+            RelayStream::Unix(ref stream) => stream.set_write_timeout(timeout),
+        }
+    }
+}
+
+impl Read for &RelayStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match **self {
+            RelayStream::Tcp(ref stream) => (&*stream).read(buf),
+            RelayStream::Unix(ref stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for RelayStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            RelayStream::Tcp(ref mut stream) => stream.write(buf),
+            RelayStream::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            RelayStream::Tcp(ref mut stream) => stream.flush(),
+            RelayStream::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+// Identifies relay connections, so that the loss of an old connection
+// is not mistaken for the loss of the current one.
+static RELAY_ID: AtomicUsize = AtomicUsize::new(0);
+
 pub struct SocketRelay {
-    stream: TcpStream,
+    id: usize,
+    address: RelayAddress,
+    stream: RelayStream,
     filter: SharedFilterFrame,
     broker: SharedMessageBroker<InternalMessage>,
 }
@@ -46,6 +213,8 @@ impl fmt::Debug for SocketRelay {
 impl Clone for SocketRelay {
     fn clone(&self) -> Self {
         SocketRelay {
+            id: self.id,
+            address: self.address.clone(),
             stream: self
                 .stream
                 .try_clone()
@@ -57,93 +226,384 @@ impl Clone for SocketRelay {
 }
 
 impl SocketRelay {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The address of the consumer.
+    pub fn address(&self) -> &RelayAddress {
+        &self.address
+    }
+
+    /// Closes the connection, which also stops the thread reading from it.
+    pub fn close(&self) {
+        let _ = self.stream.shutdown();
+    }
+
     pub fn send<T: Serialize>(&mut self, payload: &T) -> Result<()> {
-        // The client expects a JSON string with a \n ending, not a frame.
-        let mut v = serde_json::to_vec(payload).unwrap();
-        v.push(b'\n');
-        v.push(b' ');
-        self.stream.write_all(&v)?;
+        self.write_line(&relay_line(payload))
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> Result<()> {
+        self.stream.write_all(line)?;
+        STATS.bytes_relayed(line.len());
         self.stream.flush().map_err(|e| e.into())
     }
 
-    // Wait for Json string carrying the new filter.
-    pub fn listen_for_filter(&mut self) {
+    // Handles a line sent by the consumer: either an ack of relayed records
+    // or a new filter for the clients.
+    fn on_line(&self, line: &str) {
+        if let Ok(ack) = serde_json::from_str::<RelayAck>(line) {
+            let _ = self
+                .broker
+                .lock()
+                .unwrap()
+                .send_message("queue", InternalMessage::RelayAck(ack.ack));
+            return;
+        }
+
+        let new_filter: FilterFrame = match serde_json::from_str(line) {
+            Err(err) => {
+                error!("Invalid filter: {}", err);
+                return;
+            }
+            Ok(v) => v,
+        };
+        debug!("Received filter from DC App: {:?}", new_filter);
+        // Mutate the shared filter to have the new default picked up by incoming clients.
+        self.filter.lock().unwrap().set(new_filter);
+        // Dispatch a NewFilter event for already running clients.
+        self.broker
+            .lock()
+            .unwrap()
+            .broadcast_message(InternalMessage::NewFilter(new_filter));
+    }
+
+    // Wait for \n terminated Json strings carrying new filters or acks of
+    // relayed records, until the consumer closes the connection. Acks are
+    // ignored by the queue unless it runs in acked mode.
+    pub fn listen(&mut self) {
         // We want blocking reads.
         self.stream
             .set_read_timeout(None)
             .expect("Failed to set read timeout");
+        let mut reader = BufReader::new(&self.stream);
+        let mut line = String::new();
         loop {
-            // Read one line of text.
-            let buf_socket = BufReader::new(&self.stream);
-            let data: Vec<_> = buf_socket
-                .bytes()
-                .take_while(|b| match *b {
-                    Err(_) | Ok(b'\n') | Ok(b'\r') => false,
-                    _ => true,
-                })
-                .map(|e| e.unwrap())
-                .collect();
-            let s = String::from_utf8_lossy(&data);
-            debug!("Reading filter from DC App: |{}|", s);
-            let new_filter: FilterFrame = match serde_json::from_str(&s) {
+            line.clear();
+            match reader.read_line(&mut line) {
+                Ok(0) => {
+                    debug!("Relay consumer closed the connection");
+                    return;
+                }
                 Err(err) => {
-                    error!("Invalid filter: {}", err);
-                    continue;
+                    debug!("Failed to read from relay consumer: {}", err);
+                    return;
                 }
-                Ok(v) => v,
-            };
-            debug!("Received filter from DC App: {:?}", new_filter);
-            // Mutate the shared filter to have the new default picked up by incoming clients.
-            self.filter.lock().unwrap().set(new_filter);
-            // Dispatch a NewFilter event for already running clients.
-            self.broker
+                Ok(_) => {}
+            }
+
+            let line = line.trim();
+            debug!("Reading from DC App: |{}|", line);
+            if !line.is_empty() {
+                self.on_line(line);
+            }
+        }
+    }
+}
+
+// Writes the lines to the consumer. After a failure, it collects the lines
+// that were not written until the queue lets the connection go, and gives
+// them back to the queue, or returns them if the queue is gone.
+fn write_lines(
+    mut relay: SocketRelay,
+    mut resent: VecDeque<Vec<u8>>,
+    lines: Receiver<Vec<u8>>,
+) -> Vec<Vec<u8>> {
+    loop {
+        let line = match resent.pop_front() {
+            Some(line) => line,
+            None => match lines.recv() {
+                Ok(line) => line,
+                Err(_) => return vec![],
+            },
+        };
+        if let Err(err) = relay.write_line(&line) {
+            error!("Failed to relay item: {}", err);
+            // The reading thread then reports the loss of the connection.
+            relay.close();
+            let unsent: Vec<Vec<u8>> = Some(line)
+                .into_iter()
+                .chain(resent)
+                .chain(lines.iter())
+                .collect();
+            let sent = relay
+                .broker
                 .lock()
                 .unwrap()
-                .broadcast_message(InternalMessage::NewFilter(new_filter.clone()));
+                .send_message("queue", InternalMessage::RelayUnsent(relay.id, unsent.clone()));
+            return if sent.is_ok() { vec![] } else { unsent };
         }
     }
 }
 
+/// Writes to the relay consumer from its own thread, through a bounded
+/// channel, so that a consumer that doesn't read never holds the queue back.
+pub struct RelayWriter {
+    relay: SocketRelay,
+    session: String,
+    lines: SyncSender<Vec<u8>>,
+    thread: JoinHandle<Vec<Vec<u8>>>,
+}
+
+impl RelayWriter {
+    /// Starts writing to `relay`, first the `resent` records with their
+    /// relay sequence id, then up to `capacity` sent items at a time. The
+    /// records are tagged with `session`, which tells this run's sequence
+    /// ids from those of the previous runs.
+    pub fn start<'a, T, I>(relay: SocketRelay, capacity: usize, session: &str, resent: I) -> Self
+    where
+        T: 'a + Serialize,
+        I: IntoIterator<Item = &'a (u64, T)>,
+    {
+        let resent = resent
+            .into_iter()
+            .map(|&(relay_seq, ref record)| {
+                relay_line(&RelayRecord {
+                    relay_session: session,
+                    relay_seq,
+                    record,
+                })
+            })
+            .collect();
+        let (tx, rx) = sync_channel(capacity);
+        let writer = relay.clone();
+        let thread = thread::Builder::new()
+            .name("relay writer".to_owned())
+            .spawn(move || write_lines(writer, resent, rx))
+            .expect("Failed to create relay writer thread");
+        RelayWriter {
+            relay,
+            session: session.to_owned(),
+            lines: tx,
+            thread,
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.relay.id
+    }
+
+    /// Queues an item, failing with Busy if the consumer is behind.
+    pub fn send<T: Serialize>(&self, payload: &T) -> Result<()> {
+        match self.lines.try_send(relay_line(payload)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => bail!(ErrorKind::Busy),
+            Err(TrySendError::Disconnected(_)) => bail!(ErrorKind::Closed),
+        }
+    }
+
+    /// Queues a record wrapped with its relay session and sequence id.
+    pub fn send_record<T: Serialize>(&self, relay_seq: u64, record: &T) -> Result<()> {
+        self.send(&RelayRecord {
+            relay_session: &self.session,
+            relay_seq,
+            record,
+        })
+    }
+
+    /// Closes the connection. The queued lines come back with RelayUnsent.
+    pub fn close(self) {
+        self.relay.close();
+    }
+
+    /// Writes the queued lines and closes the connection, returning the
+    /// lines that could not be written within SHUTDOWN_WRITE_DEADLINE.
+    pub fn shutdown(self) -> Vec<Vec<u8>> {
+        drop(self.lines);
+        let deadline = time::Instant::now() + SHUTDOWN_WRITE_DEADLINE;
+        while !self.thread.is_finished() && time::Instant::now() < deadline {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        // A write still blocked then fails, and the thread returns the rest.
+        self.relay.close();
+        self.thread.join().unwrap_or_default()
+    }
+}
+
+/// Handle to the connection attempts of start_relay.
+pub struct RelayConnector {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl RelayConnector {
+    /// Stops trying to connect, for instance because the relay address
+    /// changed. A connection that is already up is not closed.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
 pub fn start_relay(
     config: &Config,
     broker: SharedMessageBroker<InternalMessage>,
     filter: SharedFilterFrame,
-) {
+) -> RelayConnector {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let connector = RelayConnector {
+        cancelled: cancelled.clone(),
+    };
+
     // Tries to connect to a socket, and sends it back when it's ready.
-    let port = config.relay_port;
+    let address = match config.relay_address() {
+        Ok(address) => address,
+        Err(err) => {
+            error!("Not relaying: {}", err);
+            return connector;
+        }
+    };
+    let uid = config.relay_uid.unwrap_or_else(|| unsafe { libc::getuid() });
     let filter = filter.clone();
     thread::Builder::new()
-        .name("queue manager".to_owned())
-        .spawn(move || loop {
+        .name("socket relay".to_owned())
+        .spawn(move || {
             let mut delay = 1u64;
-            debug!("Trying to connect to the socket on port {}", port);
-            match TcpStream::connect(format!("127.0.0.1:{}", port)) {
-                Err(_) => {
-                    delay = delay * 2;
-                    if delay > 10 {
-                        delay = 10;
+            while !cancelled.load(Ordering::SeqCst) {
+                debug!("Trying to connect to the socket at {}", address);
+                match RelayStream::connect(&address, uid) {
+                    Err(_) => {
+                        thread::sleep(time::Duration::new(delay, 0));
+                        delay = cmp::min(delay * 2, 10);
+                    }
+                    Ok(ref stream) if cancelled.load(Ordering::SeqCst) => {
+                        debug!("Dropping the connection to {}, it is not wanted anymore", address);
+                        let _ = stream.shutdown();
+                        break;
+                    }
+                    Ok(stream) => {
+                        debug!("Connection established");
+                        if let Err(err) = stream.set_write_timeout(Some(WRITE_TIMEOUT)) {
+                            error!("Failed to set the relay write timeout: {}", err);
+                        }
+                        let filter = filter.clone();
+                        let mut relay = SocketRelay {
+                            id: RELAY_ID.fetch_add(1, Ordering::SeqCst) + 1,
+                            address: address.clone(),
+                            stream,
+                            filter,
+                            broker: broker.clone(),
+                        };
+                        let sent = broker
+                            .lock()
+                            .unwrap()
+                            .send_message("queue", InternalMessage::RelayReady(relay.clone()));
+                        if sent.is_err() {
+                            // The queue is shutting down.
+                            relay.close();
+                            break;
+                        }
+
+                        // Once the consumer is gone, let the queue reconnect.
+                        relay.listen();
+                        let _ = broker
+                            .lock()
+                            .unwrap()
+                            .send_message("queue", InternalMessage::RelayLost(relay.id));
+                        break;
                     }
-                    thread::sleep(time::Duration::new(delay, 0));
-                }
-                Ok(stream) => {
-                    debug!("Connection established");
-                    let filter = filter.clone();
-                    let mut relay = SocketRelay {
-                        stream,
-                        filter,
-                        broker: broker.clone(),
-                    };
-                    broker
-                        .lock()
-                        .unwrap()
-                        .send_message("queue", InternalMessage::RelayReady(relay.clone()))
-                        .expect("Failed to send socket relay");
-
-                    // relay.listen_for_filter();
-                    break;
                 }
             }
             debug!("Shuting down relay startup thread.");
         })
         .expect("Failed to create socket relay thread");
+    connector
+}
+
+#[test]
+fn test_unix_relay() {
+    use frame_messages::default_shared_filterframe;
+    use message_broker::MessageBroker;
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc::channel;
+
+    let path =
+        ::std::env::temp_dir().join(format!("metrics_daemon_relay_{}", ::std::process::id()));
+    let path = path.to_str().unwrap();
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path).unwrap();
+    let mut config = Config {
+        relay_address: Some(format!("unix://{}", path)),
+        ..Config::default()
+    };
+
+    let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
+    let (tx, rx) = channel::<InternalMessage>();
+    broker.lock().unwrap().add_actor("queue", tx).unwrap();
+    let filter = default_shared_filterframe();
+    start_relay(&config, broker.clone(), filter.clone());
+
+    let (consumer, _) = listener.accept().unwrap();
+    let mut relay = match rx.recv().unwrap() {
+        InternalMessage::RelayReady(relay) => relay,
+        _ => panic!("Unexpected message"),
+    };
+    assert_eq!(relay.address(), &RelayAddress::Unix(path.into()));
+    relay.send(&json!({ "Name": "NE1" })).unwrap();
+    let mut lines = BufReader::new(consumer.try_clone().unwrap()).lines();
+    assert_eq!(lines.next().unwrap().unwrap().trim(), r#"{"Name":"NE1"}"#);
+
+    // Filters still come back from the consumer.
+    (&consumer)
+        .write_all(b"{\"NC\":1,\"ND\":2,\"NE\":3}\n")
+        .unwrap();
+    match rx.recv().unwrap() {
+        InternalMessage::NewFilter(new_filter) => assert_eq!(new_filter.ne, 3),
+        _ => panic!("Unexpected message"),
+    }
+    assert_eq!(filter.lock().unwrap().get().nd, 2);
+    relay.close();
+    match rx.recv().unwrap() {
+        InternalMessage::RelayLost(id) => assert_eq!(id, relay.id()),
+        _ => panic!("Unexpected message"),
+    }
+
+    // A consumer with another uid gets nothing.
+    config.relay_uid = Some(unsafe { libc::getuid() } + 1);
+    let connector = start_relay(&config, broker.clone(), filter);
+    let (consumer, _) = listener.accept().unwrap();
+    let mut data = vec![];
+    assert_eq!((&consumer).read_to_end(&mut data).unwrap(), 0);
+    connector.cancel();
+
+    let _ = broker.lock().unwrap().remove_actor("queue");
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+fn test_cancel_relay() {
+    use frame_messages::default_shared_filterframe;
+    use message_broker::MessageBroker;
+    use std::net::TcpListener;
+
+    // Nobody listens on the port when the relay starts.
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config = Config {
+        relay_port: port,
+        ..Config::default()
+    };
+    let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
+    start_relay(&config, broker, default_shared_filterframe()).cancel();
+    // Let a first attempt in flight fail.
+    thread::sleep(time::Duration::from_millis(200));
+
+    // The next attempts would connect, but they were cancelled.
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+    listener.set_nonblocking(true).unwrap();
+    thread::sleep(time::Duration::from_millis(3500));
+    assert!(listener.accept().is_err());
 }
@@ -0,0 +1,505 @@
+// (c) 2017 KAI OS TECHNOLOGIES (HONG KONG) LIMITED All rights reserved. This
+// file or any portion thereof may not be reproduced or used in any manner
+// whatsoever without the express written permission of KAI OS TECHNOLOGIES
+// (HONG KONG) LIMITED. KaiOS is the trademark of KAI OS TECHNOLOGIES (HONG KONG)
+// LIMITED or its affiliate company and may be registered in some jurisdictions.
+// All other trademarks are the property of their respective owners.
+
+/// Append-only on-disk spool keeping queued items while the relay is down.
+///
+/// Records are stored in numbered segment files, each record being a 32 bits
+/// big endian length, the CRC32 of the data and the data itself. Records
+/// with a bad CRC32 are skipped, and a torn tail is truncated when the spool
+/// is opened.
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, ErrorKind as IoErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+error_chain!{
+    errors {
+        RecordTooLarge(size: usize) {
+            description("Record too large for the spool")
+            display("Record of {} bytes is larger than the spool segment size", size)
+        }
+    }
+
+    foreign_links {
+        Io(::std::io::Error);
+    }
+}
+
+const SEGMENT_EXTENSION: &str = "seg";
+const RECORD_HEADER_SIZE: u64 = 8;
+
+/// CRC32 (IEEE 802.3) of a byte slice.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+struct Segment {
+    id: u64,
+    path: PathBuf,
+    bytes: u64,
+    records: usize,
+    replayed: usize, // The records already accepted by replay, which are skipped.
+    acked: usize,    // The replayed records that were delivered.
+}
+
+impl Segment {
+    // Reads all the valid records of this segment. Returns the records, the
+    // offset of the end of the last complete one, and how many complete
+    // records were skipped because their CRC32 didn't match.
+    fn read_records(&self) -> Result<(Vec<Vec<u8>>, u64, usize)> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut records = vec![];
+        let mut offset = 0u64;
+        let mut corrupted = 0;
+        loop {
+            let length = match reader.read_u32::<BigEndian>() {
+                Ok(length) => length,
+                Err(ref err) if err.kind() == IoErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            };
+            let checksum = match reader.read_u32::<BigEndian>() {
+                Ok(checksum) => checksum,
+                Err(ref err) if err.kind() == IoErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            };
+            let mut data = vec![];
+            (&mut reader).take(u64::from(length)).read_to_end(&mut data)?;
+            if data.len() != length as usize {
+                break;
+            }
+            offset += RECORD_HEADER_SIZE + u64::from(length);
+            if crc32(&data) != checksum {
+                corrupted += 1;
+                continue;
+            }
+            records.push(data);
+        }
+        Ok((records, offset, corrupted))
+    }
+}
+
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_records: usize,
+    segment_size: u64,
+    segments: VecDeque<Segment>,
+    next_id: u64,
+    dropped: usize, // Replayed records dropped before they were acknowledged.
+}
+
+impl Spool {
+    /// Opens the spool stored in `dir`, creating it if needed and dropping
+    /// any torn record left by a crash.
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        max_bytes: u64,
+        max_records: usize,
+        segment_size: u64,
+    ) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut paths = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                paths.push((id, path));
+            }
+        }
+        paths.sort();
+
+        let mut segments = VecDeque::new();
+        for (id, path) in paths {
+            let mut segment = Segment {
+                id,
+                path,
+                bytes: 0,
+                records: 0,
+                replayed: 0,
+                acked: 0,
+            };
+            let (records, offset, corrupted) = segment.read_records()?;
+            if corrupted > 0 {
+                error!(
+                    "Skipping {} corrupted records in spool segment {:?}",
+                    corrupted, segment.path
+                );
+            }
+            if offset != fs::metadata(&segment.path)?.len() {
+                error!(
+                    "Truncating corrupted spool segment {:?} at offset {}",
+                    segment.path, offset
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(&segment.path)?
+                    .set_len(offset)?;
+            }
+            segment.bytes = offset;
+            segment.records = records.len();
+            segments.push_back(segment);
+        }
+
+        let next_id = segments.back().map(|segment| segment.id + 1).unwrap_or(0);
+        let spool = Spool {
+            dir,
+            max_bytes,
+            max_records,
+            segment_size,
+            segments,
+            next_id,
+            dropped: 0,
+        };
+        info!(
+            "Opened spool at {:?} with {} records",
+            spool.dir,
+            spool.len()
+        );
+        Ok(spool)
+    }
+
+    /// The number of records currently spooled and not replayed yet.
+    pub fn len(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|segment| segment.records - segment.replayed)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The size of the spool on disk, in bytes.
+    pub fn bytes(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.bytes).sum()
+    }
+
+    fn new_segment(&mut self) -> Result<()> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let path = self.dir.join(format!("{:010}.{}", id, SEGMENT_EXTENSION));
+        File::create(&path)?.sync_all()?;
+        self.segments.push_back(Segment {
+            id,
+            path,
+            bytes: 0,
+            records: 0,
+            replayed: 0,
+            acked: 0,
+        });
+        Ok(())
+    }
+
+    // Removes the oldest segments until we are back within the configured limits.
+    // The segment being written to is always kept.
+    fn enforce_limits(&mut self) -> Result<()> {
+        while self.segments.len() > 1
+            && (self.bytes() > self.max_bytes || self.len() > self.max_records)
+        {
+            let segment = self.segments.pop_front().unwrap();
+            info!(
+                "Spool overflow, dropping segment {} with {} records",
+                segment.id, segment.records
+            );
+            self.dropped += segment.replayed - segment.acked;
+            fs::remove_file(&segment.path)?;
+        }
+        Ok(())
+    }
+
+    /// Appends a record and syncs it to disk.
+    pub fn append(&mut self, data: &[u8]) -> Result<()> {
+        let size = RECORD_HEADER_SIZE + data.len() as u64;
+        if size > self.segment_size {
+            bail!(ErrorKind::RecordTooLarge(data.len()));
+        }
+
+        let rotate = match self.segments.back() {
+            Some(segment) => segment.bytes + size > self.segment_size,
+            None => true,
+        };
+        if rotate {
+            self.new_segment()?;
+        }
+
+        {
+            let segment = self.segments.back_mut().unwrap();
+            let mut record = Vec::with_capacity(size as usize);
+            record.write_u32::<BigEndian>(data.len() as u32)?;
+            record.write_u32::<BigEndian>(crc32(data))?;
+            record.extend_from_slice(data);
+
+            let mut file = OpenOptions::new().append(true).open(&segment.path)?;
+            file.write_all(&record)?;
+            file.sync_data()?;
+            segment.bytes += size;
+            segment.records += 1;
+        }
+
+        self.enforce_limits()
+    }
+
+    /// Replays the spooled records in order. Replay stops at the first record
+    /// `send` refuses, and the next replay starts from it. The replayed records
+    /// stay on disk until they are acknowledged with `ack`.
+    pub fn replay<F>(&mut self, mut send: F) -> Result<usize>
+    where
+        F: FnMut(&[u8]) -> bool,
+    {
+        let mut count = 0;
+        for segment in self.segments.iter_mut() {
+            if segment.replayed == segment.records {
+                continue;
+            }
+            let (records, _, _) = segment.read_records()?;
+            for record in records.iter().skip(segment.replayed) {
+                if !send(record) {
+                    return Ok(count);
+                }
+                segment.replayed += 1;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Acknowledges the `count` oldest replayed records. Each segment is removed
+    /// once all its records are acknowledged. Only the counts are kept in
+    /// memory, so after a restart the records of a partially acknowledged
+    /// segment are replayed again.
+    pub fn ack(&mut self, count: usize) -> Result<()> {
+        let skipped = cmp::min(count, self.dropped);
+        self.dropped -= skipped;
+        let mut count = count - skipped;
+        while let Some(segment) = self.segments.front_mut() {
+            let acked = cmp::min(count, segment.replayed - segment.acked);
+            segment.acked += acked;
+            count -= acked;
+            if segment.acked < segment.records {
+                break;
+            }
+            fs::remove_file(&segment.path)?;
+            self.segments.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Removes every spooled record, returning how many were dropped.
+    pub fn clear(&mut self) -> Result<usize> {
+        let count = self.len();
+        while let Some(segment) = self.segments.pop_front() {
+            self.dropped += segment.replayed - segment.acked;
+            fs::remove_file(&segment.path)?;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = ::std::env::temp_dir().join(format!(
+        "metrics_spool_{}_{}",
+        name,
+        ::std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+}
+
+#[test]
+fn test_spool_replay_in_order() {
+    let dir = test_dir("replay");
+    {
+        let mut spool = Spool::open(&dir, 1024 * 1024, 1000, 64).unwrap();
+        for i in 0..10 {
+            spool.append(format!("record {}", i).as_bytes()).unwrap();
+        }
+        assert_eq!(spool.len(), 10);
+        // 8 bytes of header and 8 bytes of data, 4 records per segment.
+        assert_eq!(spool.segments.len(), 3);
+    }
+
+    // Records survive reopening the spool.
+    let mut spool = Spool::open(&dir, 1024 * 1024, 1000, 64).unwrap();
+    assert_eq!(spool.len(), 10);
+
+    // Stop in the middle of the second segment.
+    let mut replayed = vec![];
+    let count = spool
+        .replay(|record| {
+            if replayed.len() == 6 {
+                return false;
+            }
+            replayed.push(String::from_utf8(record.to_vec()).unwrap());
+            true
+        })
+        .unwrap();
+    assert_eq!(count, 6);
+    assert_eq!(spool.len(), 4);
+
+    // Segments are only removed once all their records are acknowledged.
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+    spool.ack(5).unwrap();
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+    // The next replay resumes after the accepted records.
+    replayed.clear();
+    spool
+        .replay(|record| {
+            replayed.push(String::from_utf8(record.to_vec()).unwrap());
+            true
+        })
+        .unwrap();
+    assert_eq!(replayed.first().unwrap(), "record 6");
+    assert_eq!(replayed.last().unwrap(), "record 9");
+    assert!(spool.is_empty());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+    // After a restart, the partially acknowledged segment is replayed again.
+    let mut spool = Spool::open(&dir, 1024 * 1024, 1000, 64).unwrap();
+    replayed.clear();
+    spool
+        .replay(|record| {
+            replayed.push(String::from_utf8(record.to_vec()).unwrap());
+            true
+        })
+        .unwrap();
+    assert_eq!(replayed.first().unwrap(), "record 4");
+    assert_eq!(replayed.len(), 6);
+    spool.ack(6).unwrap();
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_spool_truncates_torn_tail() {
+    let dir = test_dir("torn");
+    {
+        let mut spool = Spool::open(&dir, 1024 * 1024, 1000, 1024).unwrap();
+        spool.append(b"first").unwrap();
+        spool.append(b"second").unwrap();
+    }
+
+    // Simulate a crash in the middle of writing a record.
+    let path = dir.join("0000000000.seg");
+    {
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 42, 1, 2]).unwrap();
+    }
+
+    let mut spool = Spool::open(&dir, 1024 * 1024, 1000, 1024).unwrap();
+    assert_eq!(spool.len(), 2);
+    assert_eq!(fs::metadata(&path).unwrap().len(), spool.bytes());
+
+    // Appending after the truncation keeps the spool readable.
+    spool.append(b"third").unwrap();
+    let mut replayed = vec![];
+    spool
+        .replay(|record| {
+            replayed.push(record.to_vec());
+            true
+        })
+        .unwrap();
+    assert_eq!(
+        replayed,
+        vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_spool_limits() {
+    let dir = test_dir("limits");
+    let mut spool = Spool::open(&dir, 1024 * 1024, 6, 32).unwrap();
+    // 16 bytes records, 2 records per segment.
+    for i in 0..10 {
+        spool.append(format!("rec{:05}", i).as_bytes()).unwrap();
+    }
+    assert_eq!(spool.len(), 6);
+
+    let mut replayed = vec![];
+    spool
+        .replay(|record| {
+            replayed.push(String::from_utf8(record.to_vec()).unwrap());
+            true
+        })
+        .unwrap();
+    assert_eq!(replayed.first().unwrap(), "rec00004");
+
+    assert!(spool.append(&[0u8; 64]).is_err());
+
+    let mut spool = Spool::open(&dir, 48, 1000, 32).unwrap();
+    for i in 0..10 {
+        spool.append(format!("rec{:05}", i).as_bytes()).unwrap();
+    }
+    assert!(spool.bytes() <= 48);
+    assert_eq!(spool.clear().unwrap(), 2);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_spool_skips_corrupted_records() {
+    let dir = test_dir("corrupted");
+    {
+        let mut spool = Spool::open(&dir, 1024 * 1024, 1000, 1024).unwrap();
+        spool.append(b"first").unwrap();
+        spool.append(b"second").unwrap();
+        spool.append(b"third").unwrap();
+    }
+
+    // Flip a byte of the second record's data, after the 13 bytes of the first
+    // record and its own 8 bytes header.
+    let path = dir.join("0000000000.seg");
+    let mut data = fs::read(&path).unwrap();
+    data[13 + 8] ^= 0xFF;
+    fs::write(&path, &data).unwrap();
+
+    // Segments named without the zero padding are read too.
+    fs::rename(&path, dir.join("7.seg")).unwrap();
+
+    let mut spool = Spool::open(&dir, 1024 * 1024, 1000, 1024).unwrap();
+    assert_eq!(spool.len(), 2);
+    let mut replayed = vec![];
+    spool
+        .replay(|record| {
+            replayed.push(record.to_vec());
+            true
+        })
+        .unwrap();
+    assert_eq!(replayed, vec![b"first".to_vec(), b"third".to_vec()]);
+    assert!(spool.is_empty());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
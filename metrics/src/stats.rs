@@ -0,0 +1,360 @@
+// (c) 2017 KAI OS TECHNOLOGIES (HONG KONG) LIMITED All rights reserved. This
+// file or any portion thereof may not be reproduced or used in any manner
+// whatsoever without the express written permission of KAI OS TECHNOLOGIES
+// (HONG KONG) LIMITED. KaiOS is the trademark of KAI OS TECHNOLOGIES (HONG KONG)
+// LIMITED or its affiliate company and may be registered in some jurisdictions.
+// All other trademarks are the property of their respective owners.
+
+/// Counters about the daemon itself, served in the Prometheus text format
+/// on a local TCP port or Unix socket.
+use config::Config;
+use internal_messages::InternalMessage;
+use message_broker::SharedMessageBroker;
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::{self, BufRead, BufReader, ErrorKind as IoErrorKind, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How often we check for scrapes and for the Shutdown message.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+// Scrapes are served one at a time, so a scraper that stops sending or
+// reading can only hold the thread that long.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+type LabeledCounter = Mutex<BTreeMap<String, u64>>;
+
+pub struct Stats {
+    frames_received: LabeledCounter,     // By client source.
+    validation_failures: LabeledCounter, // By error kind.
+    queue_overflows: AtomicU64,
+    relay_reconnects: AtomicU64,
+    relay_bytes: AtomicU64,
+}
+
+/// The counters of the daemon, updated by every thread.
+pub static STATS: Stats = Stats::new();
+
+fn increment(counter: &LabeledCounter, label: &str, value: u64) {
+    *counter.lock().unwrap().entry(label.to_owned()).or_insert(0) += value;
+}
+
+// Label values are quoted, so \, " and new lines need to be escaped.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        Stats {
+            frames_received: Mutex::new(BTreeMap::new()),
+            validation_failures: Mutex::new(BTreeMap::new()),
+            queue_overflows: AtomicU64::new(0),
+            relay_reconnects: AtomicU64::new(0),
+            relay_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn frame_received(&self, source: &str) {
+        increment(&self.frames_received, source, 1);
+    }
+
+    pub fn validation_failed(&self, kind: &str) {
+        increment(&self.validation_failures, kind, 1);
+    }
+
+    pub fn queue_overflowed(&self) {
+        self.queue_overflows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn relay_reconnected(&self) {
+        self.relay_reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_relayed(&self, count: usize) {
+        self.relay_bytes.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn render_counter(output: &mut String, name: &str, help: &str, value: u64) {
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} counter", name);
+        let _ = writeln!(output, "{} {}", name, value);
+    }
+
+    fn render_labeled(
+        output: &mut String,
+        name: &str,
+        help: &str,
+        label: &str,
+        counter: &LabeledCounter,
+    ) {
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} counter", name);
+        for (value, count) in counter.lock().unwrap().iter() {
+            let _ = writeln!(
+                output,
+                "{}{{{}=\"{}\"}} {}",
+                name,
+                label,
+                escape(value),
+                count
+            );
+        }
+    }
+
+    /// Renders the counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        Stats::render_labeled(
+            &mut output,
+            "metricsd_frames_received_total",
+            "Frames received from the clients.",
+            "source",
+            &self.frames_received,
+        );
+        Stats::render_labeled(
+            &mut output,
+            "metricsd_validation_failures_total",
+            "Client records rejected with an error frame.",
+            "kind",
+            &self.validation_failures,
+        );
+        Stats::render_counter(
+            &mut output,
+            "metricsd_queue_overflows_total",
+            "Items evicted from the full queue.",
+            self.queue_overflows.load(Ordering::Relaxed),
+        );
+        Stats::render_counter(
+            &mut output,
+            "metricsd_relay_reconnects_total",
+            "Connections to the relay consumer again, at the same address.",
+            self.relay_reconnects.load(Ordering::Relaxed),
+        );
+        Stats::render_counter(
+            &mut output,
+            "metricsd_relay_bytes_total",
+            "Bytes sent to the relay consumer.",
+            self.relay_bytes.load(Ordering::Relaxed),
+        );
+        output
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
+
+// Answers a scrape: the request is read up to its empty line, and whatever
+// its path, the reply is the current counters.
+fn serve<T: Read + Write>(mut stream: T) {
+    {
+        let mut reader = BufReader::new(&mut stream);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) if line.trim().is_empty() => break,
+                Ok(_) => {}
+            }
+        }
+    }
+
+    let body = STATS.render();
+    let response = format!(
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    if let Err(err) = stream.write_all(response.as_bytes()) {
+        debug!("Failed to send the stats: {}", err);
+    }
+}
+
+// Serves the pending scrapes, returning false if there were none.
+fn accept<T: Read + Write>(accepted: io::Result<T>) -> bool {
+    match accepted {
+        Ok(stream) => {
+            serve(stream);
+            true
+        }
+        Err(ref err) if err.kind() == IoErrorKind::WouldBlock => false,
+        Err(err) => {
+            error!("Stats socket accept failed: {}", err);
+            false
+        }
+    }
+}
+
+/// Starts serving the stats on the configured localhost port and Unix socket,
+/// if any. The thread removes the socket and exits when it gets the Shutdown
+/// message.
+pub fn start_stats(
+    config: &Config,
+    broker: SharedMessageBroker<InternalMessage>,
+) -> Option<JoinHandle<()>> {
+    let tcp = config.stats_port.and_then(|port| {
+        match TcpListener::bind(("127.0.0.1", port)).and_then(|socket| {
+            socket.set_nonblocking(true)?;
+            Ok(socket)
+        }) {
+            Ok(socket) => {
+                info!("Serving stats on port {}", port);
+                Some(socket)
+            }
+            Err(err) => {
+                error!("Failed to bind the stats port {}: {}", port, err);
+                None
+            }
+        }
+    });
+
+    let path = config.stats_socket_path.clone();
+    let unix = path.as_ref().and_then(|path| {
+        if Path::new(path).exists() {
+            let _ = fs::remove_file(path);
+        }
+        match UnixListener::bind(path).and_then(|socket| {
+            socket.set_nonblocking(true)?;
+            Ok(socket)
+        }) {
+            Ok(socket) => {
+                info!("Serving stats at {}", path);
+                Some(socket)
+            }
+            Err(err) => {
+                error!("Failed to bind the stats socket {}: {}", path, err);
+                None
+            }
+        }
+    });
+
+    if tcp.is_none() && unix.is_none() {
+        return None;
+    }
+    let (tx, rx) = channel::<InternalMessage>();
+    if let Err(err) = broker.lock().unwrap().add_actor("stats", tx) {
+        error!("Failed to register the stats thread: {:?}", err);
+        return None;
+    }
+
+    let thread = thread::Builder::new()
+        .name("stats".to_owned())
+        .spawn(move || {
+            loop {
+                let mut served = false;
+                if let Some(ref socket) = tcp {
+                    served |= accept(socket.accept().and_then(|(stream, _)| {
+                        stream.set_nonblocking(false)?;
+                        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+                        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+                        Ok(stream)
+                    }));
+                }
+                if let Some(ref socket) = unix {
+                    served |= accept(socket.accept().and_then(|(stream, _)| {
+                        stream.set_nonblocking(false)?;
+                        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+                        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+                        Ok(stream)
+                    }));
+                }
+                if rx
+                    .try_iter()
+                    .any(|msg| matches!(msg, InternalMessage::Shutdown))
+                {
+                    break;
+                }
+                if !served {
+                    thread::sleep(ACCEPT_INTERVAL);
+                }
+            }
+            info!("Shutting down stats");
+            let _ = broker.lock().unwrap().remove_actor("stats");
+            if let (Some(_), Some(path)) = (unix, path) {
+                let _ = fs::remove_file(path);
+            }
+        })
+        .expect("Failed to start stats thread");
+    Some(thread)
+}
+
+#[test]
+fn render_stats() {
+    let stats = Stats::new();
+    stats.frame_received("ril");
+    stats.frame_received("ril");
+    stats.frame_received("wifi \"5G\"");
+    stats.validation_failed("InvalidRI1");
+    stats.queue_overflowed();
+    stats.bytes_relayed(120);
+    stats.bytes_relayed(80);
+
+    let output = stats.render();
+    assert!(output.contains("# TYPE metricsd_frames_received_total counter\n"));
+    assert!(output.contains("metricsd_frames_received_total{source=\"ril\"} 2\n"));
+    assert!(output.contains("metricsd_frames_received_total{source=\"wifi \\\"5G\\\"\"} 1\n"));
+    assert!(output.contains("metricsd_validation_failures_total{kind=\"InvalidRI1\"} 1\n"));
+    assert!(output.contains("metricsd_queue_overflows_total 1\n"));
+    assert!(output.contains("metricsd_relay_reconnects_total 0\n"));
+    assert!(output.contains("metricsd_relay_bytes_total 200\n"));
+}
+
+#[test]
+fn scrape_stats() {
+    use message_broker::MessageBroker;
+    use std::net::TcpStream;
+    use std::os::unix::net::UnixStream;
+
+    // A port that was free a moment ago.
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let path =
+        ::std::env::temp_dir().join(format!("metrics_daemon_stats_{}", ::std::process::id()));
+    let config = Config {
+        stats_port: Some(port),
+        stats_socket_path: Some(path.to_str().unwrap().to_owned()),
+        ..Config::default()
+    };
+    let broker: SharedMessageBroker<InternalMessage> = MessageBroker::new_shared();
+    let thread = start_stats(&config, broker.clone()).unwrap();
+    STATS.frame_received("scrape_test");
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(response.contains("metricsd_frames_received_total{source=\"scrape_test\"} 1\n"));
+
+    let mut stream = UnixStream::connect(&path).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.contains("metricsd_relay_bytes_total"));
+
+    broker
+        .lock()
+        .unwrap()
+        .broadcast_message(InternalMessage::Shutdown);
+    thread.join().unwrap();
+    assert!(!path.exists());
+}
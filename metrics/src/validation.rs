@@ -0,0 +1,442 @@
+// (c) 2017 KAI OS TECHNOLOGIES (HONG KONG) LIMITED All rights reserved. This
+// file or any portion thereof may not be reproduced or used in any manner
+// whatsoever without the express written permission of KAI OS TECHNOLOGIES
+// (HONG KONG) LIMITED. KaiOS is the trademark of KAI OS TECHNOLOGIES (HONG KONG)
+// LIMITED or its affiliate company and may be registered in some jurisdictions.
+// All other trademarks are the property of their respective owners.
+
+/// Declarative validation of the client payload fields.
+///
+/// Each field maps to a list of rules. A key ending with '*' applies to all
+/// the fields starting with that prefix, eg. "NE*" for NE1 to NE26, and
+/// exact keys take precedence over prefix keys.
+///
+/// The default rules only add the RI6 bands to the field types enforced by
+/// the payload deserialization. The strict rules also check the documented
+/// ranges and formats, and are enabled with `strict_validation`.
+///
+/// Operator profiles restrict the bands (RI6) and frequencies (RI7) on top
+/// of these rules, and are selected by the MCC/MNC of the payload (LI1).
+use config::Config;
+use frame_messages::ClientPayload;
+use serde_json::{self, Map, Value};
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum Rule {
+    Range { min: f64, max: f64 }, // Inclusive numeric range, checked on each array item.
+    Ranges { ranges: Vec<(f64, f64)> }, // Like Range, but any of the ranges is accepted.
+    OneOf { values: Vec<Value> },  // Enumeration of the allowed values.
+    Length { min: usize, max: usize }, // Inclusive string length range, in characters.
+    Format { format: StringFormat },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StringFormat {
+    Digits,      // Only ASCII digits.
+    PhoneNumber, // ASCII digits with an optional leading '+'.
+}
+
+pub type Rules = BTreeMap<String, Vec<Rule>>;
+
+/// A failed rule, reported to the client in the error frame.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Violation {
+    pub field: String,
+    pub code: String, // "Invalid<field>", or "EmptyName".
+    pub reason: String,
+}
+
+impl Violation {
+    pub fn new(field: &str, reason: String) -> Self {
+        Violation {
+            field: field.into(),
+            code: format!("Invalid{}", field),
+            reason,
+        }
+    }
+}
+
+// The modem may not have the actual band value during registration, so 0 is
+// always allowed.
+const DEFAULT_RULES: &str = r#"{
+    "RI6": [{ "rule": "one_of", "values": [0, 3, 5, 40] }]
+}"#;
+
+// The ranges follow the 3GPP encodings used by the modem.
+const STRICT_RULES: &str = r#"{
+    "DI1": [{ "rule": "format", "format": "digits" }, { "rule": "length", "min": 14, "max": 16 }],
+    "DI2": [{ "rule": "format", "format": "digits" }, { "rule": "length", "min": 6, "max": 15 }],
+    "DI3": [{ "rule": "format", "format": "phone_number" }, { "rule": "length", "min": 3, "max": 16 }],
+    "DI4": [{ "rule": "length", "min": 1, "max": 64 }],
+    "DI5": [{ "rule": "length", "min": 1, "max": 64 }],
+    "LI1": [{ "rule": "range", "min": 10000, "max": 999999 }],
+    "LI2": [{ "rule": "range", "min": 0, "max": 16777215 }],
+    "LI3": [{ "rule": "range", "min": 0, "max": 268435455 }],
+    "LI4": [{ "rule": "range", "min": 0, "max": 1007 }],
+    "LI5": [{ "rule": "range", "min": -90, "max": 90 }],
+    "LI6": [{ "rule": "range", "min": -180, "max": 180 }],
+    "LI8": [{ "rule": "range", "min": 0, "max": 100000 }],
+    "SI*": [{ "rule": "range", "min": 0, "max": 100 }],
+    "TI*": [{ "rule": "range", "min": -50, "max": 150 }],
+    "RI1": [{ "rule": "range", "min": 0, "max": 97 }],
+    "RI2": [{ "rule": "range", "min": 0, "max": 34 }],
+    "RI3": [{ "rule": "range", "min": -23, "max": 40 }],
+    "RI4": [{ "rule": "range", "min": 0, "max": 15 }],
+    "RI5": [{ "rule": "range", "min": 0, "max": 8 }],
+    "RI6": [{ "rule": "one_of", "values": [0, 3, 5, 40] }],
+    "RI9": [{ "rule": "length", "min": 1, "max": 64 }],
+    "RI11": [{ "rule": "range", "min": -60, "max": 33 }],
+    "RI12": [{ "rule": "range", "min": 0, "max": 100 }],
+    "RI13": [{ "rule": "range", "min": 0, "max": 1282 }],
+    "RI14": [{ "rule": "range", "min": -50, "max": 33 }],
+    "NI4": [{ "rule": "length", "min": 0, "max": 256 }],
+    "OI1": [{ "rule": "range", "min": -15391, "max": 15391 }],
+    "VI1": [{ "rule": "length", "min": 1, "max": 64 }],
+    "VI3": [{ "rule": "length", "min": 0, "max": 1024 }],
+    "VI4": [{ "rule": "range", "min": 0, "max": 100 }],
+    "HI*": [{ "rule": "range", "min": 0, "max": 1e12 }],
+    "NE*": [{ "rule": "length", "min": 0, "max": 1024 }],
+    "NC*": [{ "rule": "range", "min": 0, "max": 100000 }]
+}"#;
+
+#[derive(Clone, Debug)]
+pub struct ValidationTable {
+    rules: Rules,
+}
+
+impl Default for ValidationTable {
+    fn default() -> Self {
+        ValidationTable {
+            rules: serde_json::from_str(DEFAULT_RULES).expect("Invalid default rules"),
+        }
+    }
+}
+
+impl ValidationTable {
+    /// The default rules, with the given rules replacing those of the same key.
+    /// An empty list of rules disables the checks of a field.
+    pub fn new(overrides: &Rules) -> Self {
+        ValidationTable::default().with_overrides(overrides)
+    }
+
+    /// The ranges and formats of all the fields.
+    pub fn strict() -> Self {
+        ValidationTable {
+            rules: serde_json::from_str(STRICT_RULES).expect("Invalid strict rules"),
+        }
+    }
+
+    pub fn with_overrides(&self, overrides: &Rules) -> Self {
+        let mut rules = self.rules.clone();
+        for (key, value) in overrides {
+            rules.insert(key.clone(), value.clone());
+        }
+        ValidationTable { rules }
+    }
+
+    // An exact key wins, then the longest matching prefix.
+    fn rules_for(&self, field: &str) -> Option<&Vec<Rule>> {
+        if let Some(rules) = self.rules.get(field) {
+            return Some(rules);
+        }
+        self.rules
+            .iter()
+            .filter(|&(key, _)| key.ends_with('*'))
+            .filter(|&(key, _)| field.starts_with(&key[..key.len() - 1]))
+            .max_by_key(|&(key, _)| key.len())
+            .map(|(_, rules)| rules)
+    }
+
+    /// Checks all the fields of a serialized payload, in field name order.
+    pub fn check(&self, fields: &Map<String, Value>) -> Vec<Violation> {
+        let mut violations = vec![];
+        for (field, value) in fields {
+            let rules = match self.rules_for(field) {
+                Some(rules) => rules,
+                None => continue,
+            };
+            if let Some(reason) = rules.iter().filter_map(|rule| rule.check(value)).next() {
+                violations.push(Violation::new(field, reason));
+            }
+        }
+        violations
+    }
+}
+
+/// The band plan of an operator.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Profile {
+    #[serde(default)]
+    pub mcc_mnc: Vec<u32>, // The LI1 values selecting this profile.
+    pub bands: Vec<u8>, // The allowed RI6 values. 0 is always allowed.
+    #[serde(default)]
+    pub frequencies: Vec<(u16, u16)>, // The allowed RI7 ranges. 0 is always allowed.
+}
+
+impl Profile {
+    // The rules replacing the default RI6 and RI7 ones.
+    fn rules(&self) -> Rules {
+        let mut rules = Rules::new();
+
+        // The modem may not have the actual band value during registration.
+        let mut bands = vec![json!(0)];
+        bands.extend(self.bands.iter().map(|band| json!(band)));
+        rules.insert("RI6".into(), vec![Rule::OneOf { values: bands }]);
+
+        if !self.frequencies.is_empty() {
+            let mut ranges = vec![(0.0, 0.0)];
+            ranges.extend(
+                self.frequencies
+                    .iter()
+                    .map(|&(min, max)| (f64::from(min), f64::from(max))),
+            );
+            rules.insert("RI7".into(), vec![Rule::Ranges { ranges }]);
+        }
+        rules
+    }
+}
+
+/// Picks the validation table of a payload, according to the configured profiles.
+pub struct Validator {
+    table: ValidationTable, // Used when no profile applies.
+    profiles: Vec<(String, Profile, ValidationTable)>,
+    default_profile: Option<usize>, // Used when LI1 doesn't match any profile.
+}
+
+impl Validator {
+    pub fn new(config: &Config) -> Self {
+        let table = if config.strict_validation {
+            ValidationTable::strict().with_overrides(&config.validation)
+        } else {
+            ValidationTable::new(&config.validation)
+        };
+        let profiles: Vec<(String, Profile, ValidationTable)> = config
+            .profiles
+            .iter()
+            .map(|(name, profile)| {
+                let profile_table = table.with_overrides(&profile.rules());
+                (name.clone(), profile.clone(), profile_table)
+            })
+            .collect();
+
+        let default_profile = config.profile.as_ref().and_then(|name| {
+            let index = profiles.iter().position(|p| &p.0 == name);
+            if index.is_none() {
+                error!("Unknown validation profile: {}", name);
+            }
+            index
+        });
+
+        Validator {
+            table,
+            profiles,
+            default_profile,
+        }
+    }
+
+    /// Checks a payload with the table of its profile. Returns the name of the
+    /// profile if any and the violations, unless the payload is valid.
+    pub fn validate(&self, payload: &ClientPayload) -> Option<(Option<&str>, Vec<Violation>)> {
+        let (profile, table) = self.select(payload.mcc_mnc());
+        let violations = payload.violations(table);
+        if violations.is_empty() {
+            None
+        } else {
+            Some((profile, violations))
+        }
+    }
+
+    /// Returns the name of the selected profile if any, and its table.
+    pub fn select(&self, mcc_mnc: Option<u32>) -> (Option<&str>, &ValidationTable) {
+        let index = mcc_mnc
+            .and_then(|mcc_mnc| {
+                self.profiles
+                    .iter()
+                    .position(|p| p.1.mcc_mnc.contains(&mcc_mnc))
+            })
+            .or(self.default_profile);
+
+        match index {
+            Some(index) => (Some(&self.profiles[index].0), &self.profiles[index].2),
+            None => (None, &self.table),
+        }
+    }
+}
+
+impl Rule {
+    // Returns the reason of the failure, if any.
+    fn check(&self, value: &Value) -> Option<String> {
+        if let Value::Array(ref items) = *value {
+            return items.iter().filter_map(|item| self.check(item)).next();
+        }
+
+        match *self {
+            Rule::Range { min, max } => match value.as_f64() {
+                Some(number) if number >= min && number <= max => None,
+                _ => Some(format!("{} is not in the [{}, {}] range", value, min, max)),
+            },
+            Rule::Ranges { ref ranges } => match value.as_f64() {
+                Some(number) if ranges.iter().any(|r| number >= r.0 && number <= r.1) => None,
+                _ => Some(format!("{} is not in any of the {:?} ranges", value, ranges)),
+            },
+            Rule::OneOf { ref values } => {
+                if values.contains(value) {
+                    None
+                } else {
+                    Some(format!("{} is not one of {:?}", value, values))
+                }
+            }
+            Rule::Length { min, max } => match value.as_str() {
+                Some(s) if s.chars().count() >= min && s.chars().count() <= max => None,
+                _ => Some(format!("{} is not {} to {} characters long", value, min, max)),
+            },
+            Rule::Format { format } => match value.as_str() {
+                Some(s) if format.matches(s) => None,
+                _ => Some(format!("{} is not in the {:?} format", value, format)),
+            },
+        }
+    }
+}
+
+impl StringFormat {
+    fn matches(&self, s: &str) -> bool {
+        let digits = match *self {
+            StringFormat::Digits => s,
+            StringFormat::PhoneNumber if s.starts_with('+') => &s[1..],
+            StringFormat::PhoneNumber => s,
+        };
+        !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+    }
+}
+
+#[test]
+fn strict_rules() {
+    let table = ValidationTable::strict();
+    let fields = json!({
+        "Name": "NE1",
+        "DI1": "356938035643809",
+        "DI3": "+85212345678",
+        "LI5": 22.3,
+        "SI1": 57,
+        "RI1": 45,
+        "RI6": 40,
+        "OI1": [0.5, 0.2, 0.333],
+        "NE3": "call established",
+        "NC1": 3
+    });
+    assert!(table.check(fields.as_object().unwrap()).is_empty());
+
+    let fields = json!({
+        "Name": "NE1",
+        "DI1": "35693803564380x",
+        "LI5": 122.04,
+        "SI2": 101,
+        "RI6": 7,
+        "OI1": [0.5, 20000.0, 0.333]
+    });
+    let violations = table.check(fields.as_object().unwrap());
+    let codes: Vec<&str> = violations.iter().map(|v| v.code.as_str()).collect();
+    assert_eq!(
+        codes,
+        vec!["InvalidDI1", "InvalidLI5", "InvalidOI1", "InvalidRI6", "InvalidSI2"]
+    );
+    assert_eq!(violations[3].field, "RI6");
+}
+
+#[test]
+fn override_rules() {
+    let overrides: Rules = serde_json::from_value(json!({
+        "RI6": [{ "rule": "one_of", "values": [0, 3, 5, 7, 40] }],
+        "SI*": []
+    }))
+    .unwrap();
+    let table = ValidationTable::strict().with_overrides(&overrides);
+
+    let fields = json!({ "RI6": 7, "SI2": 101, "RI1": 120 });
+    let violations = table.check(fields.as_object().unwrap());
+    assert_eq!(violations.len(), 1);
+    assert_eq!(violations[0].code, "InvalidRI1");
+}
+
+#[test]
+fn longest_prefix_rules() {
+    // "NC1*" sorts before "NC*", and "N*" before both.
+    let overrides: Rules = serde_json::from_value(json!({
+        "N*": [{ "rule": "range", "min": 0, "max": 1 }],
+        "NC1*": [{ "rule": "range", "min": 0, "max": 10 }]
+    }))
+    .unwrap();
+    let table = ValidationTable::strict().with_overrides(&overrides);
+
+    let fields = json!({ "NC12": 5, "NC2": 500, "NX1": 1 });
+    assert!(table.check(fields.as_object().unwrap()).is_empty());
+
+    let fields = json!({ "NC12": 50, "NC2": 500000, "NX1": 2 });
+    let violations = table.check(fields.as_object().unwrap());
+    let codes: Vec<&str> = violations.iter().map(|v| v.code.as_str()).collect();
+    assert_eq!(codes, vec!["InvalidNC12", "InvalidNC2", "InvalidNX1"]);
+}
+
+#[test]
+fn select_profile() {
+    let config: Config = serde_json::from_value(json!({
+        "socket_path": "/tmp/metricsd_socket",
+        "mqtt_host": "localhost:1883",
+        "buffer_size": 10,
+        "relay_port": 12345,
+        "verbose": false,
+        "profile": "jio",
+        "profiles": {
+            "jio": { "mcc_mnc": [405840, 405857], "bands": [3, 5, 40] },
+            "other": {
+                "mcc_mnc": [46001],
+                "bands": [1, 3],
+                "frequencies": [[0, 599], [1200, 1949]]
+            }
+        }
+    }))
+    .unwrap();
+    let validator = Validator::new(&config);
+
+    let (name, table) = validator.select(Some(46001));
+    assert_eq!(name, Some("other"));
+    let fields = json!({ "RI6": 1, "RI7": 1300 });
+    assert!(table.check(fields.as_object().unwrap()).is_empty());
+    let fields = json!({ "RI6": 40, "RI7": 1000 });
+    let violations = table.check(fields.as_object().unwrap());
+    let codes: Vec<&str> = violations.iter().map(|v| v.code.as_str()).collect();
+    assert_eq!(codes, vec!["InvalidRI6", "InvalidRI7"]);
+
+    // Unknown and missing LI1 values use the default profile.
+    for mcc_mnc in &[Some(405840), Some(12345), None] {
+        let (name, table) = validator.select(*mcc_mnc);
+        assert_eq!(name, Some("jio"));
+        let fields = json!({ "RI6": 1, "RI7": 1000 });
+        assert_eq!(table.check(fields.as_object().unwrap()).len(), 1);
+    }
+
+    // Without profiles, the default rules apply.
+    let validator = Validator::new(&Config::default());
+    let (name, table) = validator.select(Some(46001));
+    assert_eq!(name, None);
+    let fields = json!({ "RI6": 40 });
+    assert!(table.check(fields.as_object().unwrap()).is_empty());
+}
+
+#[test]
+fn default_rules_accept_fixtures() {
+    use frame_messages::{ClientMessage, FULL_MESSAGE, SAMPLE_MESSAGE};
+
+    let validator = Validator::new(&Config::default());
+    for input in &[SAMPLE_MESSAGE, FULL_MESSAGE] {
+        let message: ClientMessage = serde_json::from_str(input).unwrap();
+        assert_eq!(validator.validate(&message.payload), None);
+    }
+
+    // The default bands are still checked.
+    let (_, violations) = validator.validate(&ClientPayload::bad_ri6()).unwrap();
+    assert_eq!(violations[0].code, "InvalidRI6");
+}